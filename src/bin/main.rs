@@ -1,10 +1,7 @@
 #[macro_use]
 extern crate log;
 
-use delivery_station::{
-    constants::CONFIG,
-    http::new_server,
-};
+use delivery_station::{constants::CONFIG, http::new_server, scheduler};
 
 #[tokio::main]
 async fn main() {
@@ -14,6 +11,7 @@ async fn main() {
         Ok(s) => s,
         Err(e) => panic!("{}", e),
     };
+    scheduler::start(&CONFIG);
     info!("listening on {}", addr);
     if let Err(e) = http_server.serve().await {
         error!("server error: {}", e);
@@ -0,0 +1,360 @@
+use std::convert::TryFrom;
+
+use anyhow::{anyhow, bail, Error, Result};
+use regex::Regex;
+use serde::Deserialize;
+
+/// condition of running a step, one of:
+///
+/// * `on_success`, run if all the previous steps succeeded, the default
+/// * `on_failure`, run if any of the previous steps failed
+/// * `always`, run whether the previous steps succeeded or not
+/// * an expression, e.g. `tag =~ "^v"`, `branch == "main" && env.DEPLOY_ENV != "prod"`,
+///   `on_failure && branch == "main"`
+///
+/// an expression compares a variable with a string by `==` and `!=`,
+/// or with a regex by `=~` and `!~`, the comparisons and the states above are combined
+/// by `!`, `&&`, `||` and parentheses. an expression without any state is
+/// implicitly `on_success && (expression)`
+///
+/// variables are `repository`, `branch`, `tag`, `event`, `steps_name` and
+/// `env.<NAME>` for the environment variables of the step, a missing variable
+/// equals to nothing
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Condition {
+    expr: Box<Expr>,
+    /// the expression contains any of `on_success`, `on_failure` and `always`
+    has_state: bool,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    State(State),
+    Compare(String, Operator),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum State {
+    OnSuccess,
+    OnFailure,
+    Always,
+}
+
+#[derive(Debug, Clone)]
+enum Operator {
+    Equal(String),
+    NotEqual(String),
+    Match(Regex),
+    NotMatch(Regex),
+}
+
+impl Condition {
+    /// `failed` is whether any of the previous steps failed,
+    /// `variable` returns the value of a variable
+    pub fn is_match<'a>(&self, failed: bool, variable: impl Fn(&str) -> Option<&'a str>) -> bool {
+        let matched = self.expr.evaluate(failed, &variable);
+        if self.has_state {
+            matched
+        } else {
+            !failed && matched
+        }
+    }
+}
+
+impl Default for Condition {
+    fn default() -> Condition {
+        Condition {
+            expr: Box::new(Expr::State(State::OnSuccess)),
+            has_state: true,
+        }
+    }
+}
+
+impl Expr {
+    fn evaluate<'a>(&self, failed: bool, variable: &impl Fn(&str) -> Option<&'a str>) -> bool {
+        match self {
+            Expr::State(State::OnSuccess) => !failed,
+            Expr::State(State::OnFailure) => failed,
+            Expr::State(State::Always) => true,
+            Expr::Compare(name, operator) => {
+                let value = variable(name);
+                match operator {
+                    Operator::Equal(s) => value == Some(s.as_str()),
+                    Operator::NotEqual(s) => value != Some(s.as_str()),
+                    Operator::Match(r) => value.is_some_and(|v| r.is_match(v)),
+                    Operator::NotMatch(r) => !value.is_some_and(|v| r.is_match(v)),
+                }
+            }
+            Expr::Not(e) => !e.evaluate(failed, variable),
+            Expr::And(l, r) => l.evaluate(failed, variable) && r.evaluate(failed, variable),
+            Expr::Or(l, r) => l.evaluate(failed, variable) || r.evaluate(failed, variable),
+        }
+    }
+
+    fn has_state(&self) -> bool {
+        match self {
+            Expr::State(_) => true,
+            Expr::Compare(..) => false,
+            Expr::Not(e) => e.has_state(),
+            Expr::And(l, r) | Expr::Or(l, r) => l.has_state() || r.has_state(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Op(&'static str),
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>> {
+    const OPERATORS: [&str; 9] = ["==", "!=", "=~", "!~", "&&", "||", "!", "(", ")"];
+    let mut tokens = Vec::new();
+    let mut rest = source.trim_start();
+    while let Some(c) = rest.chars().next() {
+        if let Some(op) = OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+            tokens.push(Token::Op(op));
+            rest = &rest[op.len()..];
+        } else if c == '"' {
+            // only `\"` and `\\` are escaped, other backslashes are kept for regex
+            let mut s = String::new();
+            let mut chars = rest[1..].char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((i, '"')) => break i + 2,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, c @ '"')) | Some((_, c @ '\\')) => s.push(c),
+                        Some((_, c)) => {
+                            s.push('\\');
+                            s.push(c);
+                        }
+                        None => bail!("unterminated string in \"{}\"", source),
+                    },
+                    Some((_, c)) => s.push(c),
+                    None => bail!("unterminated string in \"{}\"", source),
+                }
+            };
+            tokens.push(Token::Str(s));
+            rest = &rest[end..];
+        } else if c.is_ascii_alphanumeric() || c == '_' {
+            let end = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '.'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else {
+            bail!("unexpected character '{}' in \"{}\"", c, source);
+        }
+        rest = rest.trim_start();
+    }
+    Ok(tokens)
+}
+
+/// a recursive descent parser, `||` has lower precedence than `&&`
+struct Parser {
+    tokens: std::vec::IntoIter<Token>,
+    peeked: Option<Token>,
+}
+
+impl Parser {
+    fn peek(&mut self) -> Option<&Token> {
+        if self.peeked.is_none() {
+            self.peeked = self.tokens.next();
+        }
+        self.peeked.as_ref()
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        self.peeked.take().or_else(|| self.tokens.next())
+    }
+
+    fn eat(&mut self, op: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Op(o)) if *o == op) {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or(&mut self) -> Result<Expr> {
+        let mut expr = self.and()?;
+        while self.eat("||") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+        Ok(expr)
+    }
+
+    fn and(&mut self) -> Result<Expr> {
+        let mut expr = self.unary()?;
+        while self.eat("&&") {
+            expr = Expr::And(Box::new(expr), Box::new(self.unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        if self.eat("!") {
+            return Ok(Expr::Not(Box::new(self.unary()?)));
+        }
+        if self.eat("(") {
+            let expr = self.or()?;
+            if !self.eat(")") {
+                bail!("missing ')'");
+            }
+            return Ok(expr);
+        }
+        let name = match self.next() {
+            Some(Token::Ident(name)) => name,
+            t => bail!("expect a variable, found {:?}", t),
+        };
+        let state = match name.as_str() {
+            "on_success" => Some(State::OnSuccess),
+            "on_failure" => Some(State::OnFailure),
+            "always" => Some(State::Always),
+            _ => None,
+        };
+        if let Some(state) = state {
+            return Ok(Expr::State(state));
+        }
+        match name.as_str() {
+            "repository" | "branch" | "tag" | "event" | "steps_name" => {}
+            n if n.strip_prefix("env.").is_some_and(|n| !n.is_empty()) => {}
+            n => bail!("unknown variable \"{}\"", n),
+        }
+        let op = match self.next() {
+            Some(Token::Op(op)) => op,
+            t => bail!("expect an operator after \"{}\", found {:?}", name, t),
+        };
+        let value = match self.next() {
+            Some(Token::Str(s)) => s,
+            t => bail!("expect a string after \"{}\", found {:?}", op, t),
+        };
+        let operator = match op {
+            "==" => Operator::Equal(value),
+            "!=" => Operator::NotEqual(value),
+            "=~" => Operator::Match(Regex::new(&value)?),
+            "!~" => Operator::NotMatch(Regex::new(&value)?),
+            op => bail!("unexpected operator \"{}\"", op),
+        };
+        Ok(Expr::Compare(name, operator))
+    }
+}
+
+impl TryFrom<String> for Condition {
+    type Error = Error;
+
+    fn try_from(source: String) -> Result<Condition> {
+        let mut parser = Parser {
+            tokens: tokenize(&source)?.into_iter(),
+            peeked: None,
+        };
+        let expr = parser
+            .or()
+            .map_err(|e| anyhow!("invalid condition \"{}\": {}", source, e))?;
+        if let Some(t) = parser.next() {
+            bail!("invalid condition \"{}\": unexpected {:?}", source, t);
+        }
+        let has_state = expr.has_state();
+        Ok(Condition {
+            expr: Box::new(expr),
+            has_state,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn condition(source: &str) -> Condition {
+        Condition::try_from(source.to_string()).unwrap()
+    }
+
+    fn is_match(source: &str, failed: bool, branch: &str, tag: Option<&'static str>) -> bool {
+        condition(source).is_match(failed, |name| match name {
+            "branch" => Some(branch),
+            "tag" => tag,
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn states() {
+        assert!(Condition::default().is_match(false, |_| None));
+        assert!(!Condition::default().is_match(true, |_| None));
+        assert!(!is_match("on_failure", false, "main", None));
+        assert!(is_match("on_failure", true, "main", None));
+        assert!(is_match("always", true, "main", None));
+    }
+
+    #[test]
+    fn implicit_on_success() {
+        assert!(is_match("branch == \"main\"", false, "main", None));
+        assert!(!is_match("branch == \"main\"", true, "main", None));
+        assert!(!is_match("!(branch != \"main\")", true, "main", None));
+        assert!(is_match("always && branch == \"main\"", true, "main", None));
+        assert!(is_match(
+            "on_failure && branch == \"main\"",
+            true,
+            "main",
+            None
+        ));
+        assert!(!is_match(
+            "on_failure && branch == \"main\"",
+            false,
+            "main",
+            None
+        ));
+    }
+
+    #[test]
+    fn precedence() {
+        // `&&` binds tighter than `||`
+        let source = "branch == \"dev\" || branch == \"main\" && tag =~ \"^v\"";
+        assert!(is_match(source, false, "dev", None));
+        assert!(!is_match(source, false, "main", None));
+        assert!(is_match(source, false, "main", Some("v1.0")));
+        let source = "(branch == \"dev\" || branch == \"main\") && tag =~ \"^v\"";
+        assert!(!is_match(source, false, "dev", None));
+        // `!` binds tighter than `&&`
+        let source = "!on_failure && branch == \"main\"";
+        assert!(is_match(source, false, "main", None));
+        assert!(!is_match(source, true, "main", None));
+        assert!(!is_match(source, false, "dev", None));
+    }
+
+    #[test]
+    fn missing_variable() {
+        assert!(is_match("tag != \"v1\"", false, "main", None));
+        assert!(!is_match("tag =~ \".*\"", false, "main", None));
+        assert!(is_match("tag !~ \"^v\"", false, "main", None));
+    }
+
+    #[test]
+    fn invalid() {
+        for source in &[
+            "",
+            "branch",
+            "branch ==",
+            "branch == main",
+            "unknown == \"x\"",
+            "env. == \"x\"",
+            "(on_success",
+            "on_success on_failure",
+            "tag =~ \"[\"",
+            "tag == \"v1",
+        ] {
+            assert!(
+                Condition::try_from(source.to_string()).is_err(),
+                "{}",
+                source
+            );
+        }
+    }
+}
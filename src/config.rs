@@ -1,15 +1,20 @@
-use std::{collections::HashMap, fs::File, slice::from_ref};
+use std::{collections::HashMap, convert::TryFrom, fs::File, slice::from_ref};
 
-use anyhow::Result;
+use anyhow::{anyhow, Error, Result};
 use serde::Deserialize;
 use serde_yaml::{from_reader, Value};
 
+use crate::{condition::Condition, pattern::Filter, scheduler::Cron};
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Config {
     /// delivery station http server listen address
     pub listen_address: String,
     /// SSH host
     pub host: HashMap<String, Host>,
+    /// named lists of hosts, e.g. `web: [web1, web2, web3]`, a step targeting a group runs on
+    /// every member
+    pub host_group: Option<HashMap<String, Vec<String>>>,
     /// git repository list
     pub repository: Vec<Repository>,
     /// notifier list
@@ -20,10 +25,78 @@ pub struct Config {
     pub work_dir: Option<String>,
     /// delivery station http server url prefix
     pub base_url: Option<String>,
+    /// webhook trigger config
+    pub trigger: Option<Trigger>,
+    /// global limit of runs
+    pub worker: Option<Worker>,
+    /// default timeout of a step in seconds
+    pub timeout: Option<u64>,
     /// extra config
     pub extra: Option<Value>,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct Worker {
+    /// max number of concurrent runs, unlimited if missing
+    pub max_concurrency: Option<usize>,
+    /// new triggers are rejected with `503` if the number of runs not started reaches it
+    pub max_backlog: Option<usize>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Trigger {
+    /// coding.net service hook
+    pub coding: Option<WebhookSecret>,
+    /// github webhook
+    pub github: Option<WebhookSecret>,
+    /// gitlab webhook, `secret` is compared with the `X-Gitlab-Token` header
+    pub gitlab: Option<WebhookSecret>,
+    /// generic webhook endpoints
+    pub generic: Option<Vec<GenericTrigger>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct WebhookSecret {
+    pub secret: String,
+}
+
+/// a webhook endpoint accept any json body,
+/// fields of `TriggeredInfo` are extracted by JSON pointer, e.g. `/repository/name`
+#[derive(Debug, Clone, Deserialize)]
+pub struct GenericTrigger {
+    /// http server path, e.g. `/harbor_trigger`
+    pub path: String,
+    pub auth: GenericAuth,
+    pub repository: String,
+    pub branch: Option<String>,
+    pub tag: Option<String>,
+    pub steps_name: Option<String>,
+    /// extra fields exported to steps, environment variable name to JSON pointer
+    pub environment: Option<HashMap<String, String>>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum GenericAuth {
+    /// the value of `header` must equal to `token`
+    Token { header: String, token: String },
+    /// the value of `header` must equal to `prefix` followed by hex encoded HMAC of body
+    Hmac {
+        header: String,
+        algorithm: HmacAlgorithm,
+        secret: String,
+        prefix: Option<String>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HmacAlgorithm {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename = "snake_case", tag = "type", content = "config")]
 pub enum Notifier {
@@ -39,6 +112,60 @@ pub struct Host {
     pub hostname: String,
     pub port: Option<u16>,
     pub user: String,
+    /// public key of the host, e.g. `ssh-ed25519 AAAAC3Nz...`, `known_hosts_file` is not used
+    /// if it is set
+    pub host_key: Option<String>,
+    /// default is `~/.ssh/known_hosts`
+    pub known_hosts_file: Option<String>,
+    /// how to handle a host key not found in `known_hosts_file`, default is `strict`
+    #[serde(default)]
+    pub host_key_check: HostKeyCheck,
+    /// private key file, `~/.ssh/id_ed25519` or `~/.ssh/id_rsa` is used if no
+    /// authentication method is configured
+    pub identity_file: Option<String>,
+    /// passphrase of `identity_file`
+    pub passphrase: Option<Secret>,
+    /// authenticate with the keys of `ssh-agent` from `SSH_AUTH_SOCK`
+    #[serde(default)]
+    pub use_agent: bool,
+    pub password: Option<Secret>,
+    /// name of the host to connect through, it may have its own `proxy_jump`
+    pub proxy_jump: Option<String>,
+}
+
+/// a secret read from an environment variable, e.g. `env: DEPLOY_PASSWORD`,
+/// or from a file, e.g. `file: /run/secrets/deploy_password`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Secret {
+    Env(String),
+    File(String),
+}
+
+impl Secret {
+    /// the trailing newline of a file is removed
+    pub fn read(&self) -> Result<String> {
+        match self {
+            Secret::Env(name) => std::env::var(name)
+                .map_err(|_| anyhow!("\"{}\" environment variable is required", name)),
+            Secret::File(path) => {
+                let secret = std::fs::read_to_string(path)
+                    .map_err(|e| anyhow!("failed to read \"{}\": {}", path, e))?;
+                Ok(secret.trim_end_matches(&['\r', '\n'][..]).to_string())
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HostKeyCheck {
+    /// reject unknown host keys
+    #[default]
+    Strict,
+    /// trust on first use, unknown host keys are added to `known_hosts_file`,
+    /// changed host keys are still rejected
+    AcceptNew,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -46,34 +173,206 @@ pub struct Repository {
     pub name: String,
     pub description: Option<String>,
     pub environment: Option<HashMap<String, String>>,
+    pub branch: Option<Filter>,
+    pub tag: Option<Filter>,
+    /// webhook events to react, event name to steps name,
+    /// e.g. `pull_request.opened: preview`, only `push` is reacted when missing
+    pub events: Option<HashMap<String, Option<String>>>,
+    /// pick steps name by branch or tag when it is not specified, the first matched rule is used
+    pub steps_rules: Option<Vec<StepsRule>>,
+    /// run only if any changed file is matched
+    pub paths: Option<Filter>,
+    /// skip if all changed files are matched
+    pub paths_ignore: Option<Filter>,
+    /// run periodically, e.g. `0 3 * * *`
+    pub schedule: Option<Schedule>,
+    /// how to handle a new run while the previous one is running, default is `queue`
+    pub concurrency: Option<Concurrency>,
+    steps: CompositeSteps,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Concurrency {
+    #[serde(default)]
+    pub policy: ConcurrencyPolicy,
+    /// runs of different steps name are independent
+    #[serde(default)]
+    pub per_steps: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ConcurrencyPolicy {
+    /// run one by one
+    #[default]
+    Queue,
+    /// cancel the running one, its local and remote processes are killed before the new one starts
+    CancelPrevious,
+    /// keep only the newest pending run
+    Coalesce,
+}
+
+/// a cron expression, or a map with `cron` and the options
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "ScheduleForm")]
+pub struct Schedule {
+    pub cron: Cron,
     pub branch: Option<String>,
     pub tag: Option<String>,
-    steps: CompositeSteps,
+    pub steps_name: Option<String>,
+    /// what to do with the ticks missed while delivery station is down
+    pub missed: Missed,
+}
+
+/// the cron expression is parsed after the form is matched, an untagged enum of `Cron`
+/// would hide the error of an invalid expression behind "did not match any variant"
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum ScheduleForm {
+    Cron(String),
+    Detailed {
+        cron: String,
+        branch: Option<String>,
+        tag: Option<String>,
+        steps_name: Option<String>,
+        #[serde(default)]
+        missed: Missed,
+    },
+}
+
+impl TryFrom<ScheduleForm> for Schedule {
+    type Error = Error;
+
+    fn try_from(form: ScheduleForm) -> Result<Schedule> {
+        Ok(match form {
+            ScheduleForm::Cron(cron) => Schedule {
+                cron: Cron::try_from(cron)?,
+                branch: None,
+                tag: None,
+                steps_name: None,
+                missed: Missed::default(),
+            },
+            ScheduleForm::Detailed {
+                cron,
+                branch,
+                tag,
+                steps_name,
+                missed,
+            } => Schedule {
+                cron: Cron::try_from(cron)?,
+                branch,
+                tag,
+                steps_name,
+                missed,
+            },
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Missed {
+    #[default]
+    Skip,
+    /// run once at startup no matter how many ticks are missed
+    RunOnce,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StepsRule {
+    pub branch: Option<Filter>,
+    pub tag: Option<Filter>,
+    pub steps_name: String,
+}
+
+impl StepsRule {
+    fn is_match(&self, branch: Option<&str>, tag: Option<&str>) -> bool {
+        self.branch.as_ref().is_none_or(|b| b.is_match(branch))
+            && self.tag.as_ref().is_none_or(|t| t.is_match(tag))
+    }
 }
 
 impl Repository {
+    /// `description` or position of this entry in config, used to report triggered entries
+    pub fn label(&self, index: usize) -> String {
+        match &self.description {
+            Some(d) => format!("#{} {}", index, d),
+            None => format!("#{}", index),
+        }
+    }
+
+    pub fn match_steps_name(&self, branch: Option<&str>, tag: Option<&str>) -> Option<&str> {
+        self.steps_rules
+            .iter()
+            .flatten()
+            .find(|r| r.is_match(branch, tag))
+            .map(|r| r.steps_name.as_str())
+    }
+
+    /// check changed files against path filters of this entry and of the steps
+    pub fn match_paths(&self, steps_name: Option<&str>, files: &[String]) -> bool {
+        if !match_paths(self.paths.as_ref(), self.paths_ignore.as_ref(), files) {
+            return false;
+        }
+        match self.get_steps_set(steps_name) {
+            Some(Steps::Detailed(d)) => {
+                match_paths(d.paths.as_ref(), d.paths_ignore.as_ref(), files)
+            }
+            _ => true,
+        }
+    }
+
+    /// timeout of all steps in seconds
+    pub fn get_steps_timeout(&self, steps_name: Option<&str>) -> Option<u64> {
+        match self.get_steps_set(steps_name) {
+            Some(Steps::Detailed(d)) => d.timeout,
+            _ => None,
+        }
+    }
+
     pub fn get_steps(&self, steps_name: Option<&str>) -> Option<&[Step]> {
+        self.get_steps_set(steps_name).and_then(|s| s.get())
+    }
+
+    fn get_steps_set(&self, steps_name: Option<&str>) -> Option<&Steps> {
         match &self.steps {
             CompositeSteps::Multiple(m) => {
                 let steps_name = steps_name.unwrap_or("default");
-                m.get(steps_name).and_then(|s| s.get())
+                m.get(steps_name)
             }
-            CompositeSteps::Single(s) => s.get(),
+            CompositeSteps::Single(s) => Some(s),
         }
     }
 }
 
+fn match_paths(paths: Option<&Filter>, paths_ignore: Option<&Filter>, files: &[String]) -> bool {
+    if let Some(paths) = paths {
+        if !files.iter().any(|f| paths.is_match(Some(f))) {
+            return false;
+        }
+    }
+    if let Some(paths_ignore) = paths_ignore {
+        if files.iter().all(|f| paths_ignore.is_match(Some(f))) {
+            return false;
+        }
+    }
+    true
+}
+
+// loaded once with the config, the size does not matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Clone, Deserialize)]
 #[serde(untagged)]
 enum CompositeSteps {
-    Multiple(HashMap<String, Steps>),
     Single(Steps),
+    Multiple(HashMap<String, Steps>),
 }
 
 #[derive(Debug, Clone, Deserialize)]
 #[serde(untagged)]
 enum Steps {
     Multiple(Vec<Step>),
+    Detailed(StepsSet),
     Single(Step),
 }
 
@@ -81,22 +380,109 @@ impl Steps {
     pub fn get(&self) -> Option<&[Step]> {
         Some(match self {
             Steps::Multiple(m) => m,
+            Steps::Detailed(d) => &d.steps,
             Steps::Single(s) => from_ref(s),
         })
     }
 }
 
+/// steps with options
+#[derive(Debug, Clone, Deserialize)]
+struct StepsSet {
+    /// run only if any changed file is matched
+    paths: Option<Filter>,
+    /// skip if all changed files are matched
+    paths_ignore: Option<Filter>,
+    /// timeout of all steps in seconds
+    timeout: Option<u64>,
+    steps: Vec<Step>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub struct Step {
+    /// referred by `needs` of other steps, `name` is the script name of a `script` step
+    pub id: Option<String>,
     pub description: Option<String>,
+    /// a host or a host group
     pub host: Option<String>,
+    /// how to run on the members of a host group, default is `sequential`
+    #[serde(default)]
+    pub strategy: Strategy,
     pub current_dir: Option<String>,
     pub environment: Option<HashMap<String, String>>,
+    /// timeout in seconds, the local process group is killed or the SSH channel is closed
+    pub timeout: Option<u64>,
+    pub retry: Option<Retry>,
+    /// run this step `on_success` (default), `on_failure`, `always` or by an expression,
+    /// see `Condition`
+    #[serde(default)]
+    pub when: Condition,
+    /// ids of the previous steps to wait for, the steps without dependencies
+    /// (`needs: []`) or with the same dependencies run in parallel,
+    /// the previous step is waited for when missing
+    pub needs: Option<Vec<String>>,
     #[serde(flatten)]
     pub action: Action,
 }
 
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    /// one host at a time, stop on the first failure
+    #[default]
+    Sequential,
+    /// all hosts at once
+    Parallel,
+    /// batches of N hosts, e.g. `rolling: 2`, stop after the batch with the first failure
+    Rolling(usize),
+}
+
+impl Strategy {
+    /// number of hosts run at once
+    pub fn batch_size(&self, hosts: usize) -> usize {
+        match self {
+            Strategy::Sequential => 1,
+            Strategy::Parallel => hosts.max(1),
+            Strategy::Rolling(n) => (*n).max(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Retry {
+    /// max number of attempts, including the first one
+    pub attempts: u32,
+    /// delay before the first retry in seconds, a negative delay is ignored
+    #[serde(default)]
+    pub delay: f64,
+    /// the delay is multiplied by it after each retry
+    #[serde(default = "Retry::default_backoff")]
+    pub backoff: f64,
+    /// retry on all kinds of failure below when missing, other errors, e.g. a missing script
+    /// or an SSH error after the command is started, are never retried
+    pub on: Option<Vec<RetryOn>>,
+}
+
+impl Retry {
+    fn default_backoff() -> f64 {
+        1.0
+    }
+
+    pub fn is_retry_on(&self, on: RetryOn) -> bool {
+        self.on.as_ref().is_none_or(|o| o.contains(&on))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    /// SSH connect, handshake or authentication failed, the command is not started
+    ConnectError,
+    /// exited with nonzero status or timed out
+    NonzeroExit,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "snake_case", tag = "kind")]
 pub enum Action {
@@ -139,3 +525,31 @@ impl Config {
         Ok(config)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_schedule_error() {
+        for source in &["0 25 * * *", "{cron: 0 25 * * *, branch: main}"] {
+            let e = serde_yaml::from_str::<Schedule>(source)
+                .unwrap_err()
+                .to_string();
+            assert!(e.contains("invalid cron field \"25\""), "{}", e);
+        }
+        let schedule: Schedule = serde_yaml::from_str("{cron: \"@daily\", tag: v1}").unwrap();
+        assert_eq!(schedule.cron.expression(), "@daily");
+        assert_eq!(schedule.tag.as_deref(), Some("v1"));
+    }
+
+    #[test]
+    fn step_id_and_script_name() {
+        let step: Step = serde_yaml::from_str("{id: deploy, kind: script, name: abc.sh}").unwrap();
+        assert_eq!(step.id.as_deref(), Some("deploy"));
+        match step.action {
+            Action::Script { name } => assert_eq!(name.get_name(), "abc.sh"),
+            a => panic!("unexpected action {:?}", a),
+        }
+    }
+}
@@ -63,9 +63,26 @@ impl<'a> Step {
             ("TRIGGERED_INFO_BRANCH", ti.branch.as_deref()),
             ("TRIGGERED_INFO_TAG", ti.tag.as_deref()),
             ("TRIGGERED_INFO_STEPS_NAME", ti.steps_name.as_deref()),
+            ("TRIGGERED_INFO_EVENT", ti.event.as_deref()),
+            ("TRIGGERED_INFO_BEFORE", ti.metadata.before.as_deref()),
+            ("TRIGGERED_INFO_AFTER", ti.metadata.after.as_deref()),
+            (
+                "TRIGGERED_INFO_COMPARE_URL",
+                ti.metadata.compare_url.as_deref(),
+            ),
+            ("TRIGGERED_INFO_PUSHER", ti.metadata.pusher.as_deref()),
+            ("TRIGGERED_INFO_MESSAGE", ti.metadata.message.as_deref()),
+            ("TRIGGERED_INFO_AUTHOR", ti.metadata.author.as_deref()),
+            ("TRIGGERED_INFO_CLONE_URL", ti.metadata.clone_url.as_deref()),
         ]
         .iter()
         .filter_map(|(k, v)| v.as_ref().map(|v| (*k, *v)))
+        .chain(
+            ti.environment
+                .iter()
+                .flatten()
+                .map(|(k, v)| (k.as_str(), v.as_str())),
+        )
         .collect();
 
         Environment {
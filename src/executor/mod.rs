@@ -1,12 +1,19 @@
 use std::{
+    collections::HashMap,
     path::{Path, PathBuf},
-    process::Output,
+    process::Stdio,
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, bail, Context, Result};
 use blocking::unblock;
+use futures::{
+    future::join_all,
+    stream::{FuturesUnordered, StreamExt},
+};
 use http::{Request, Response};
 use hyper::{Body, Error};
+use log::warn;
 use serde::Deserialize;
 use serde::Serialize;
 use tokio::{
@@ -14,34 +21,92 @@ use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
 };
 
+use self::{
+    output::{Output, Stream},
+    pool::SessionPool,
+    ssh::ConnectError,
+};
 use crate::{
-    config::{Action, Config, Repository, Step},
+    config::{Action, Config, Repository, RetryOn, Step},
     constants::CONFIG,
     trigger::TriggeredInfo,
 };
 
 mod environment;
+mod output;
+mod pool;
+pub mod queue;
 mod ssh;
+pub mod worker;
+
+pub use worker::status_handler;
+
+/// exit status of a timed out step, same as the `timeout` command
+const TIMED_OUT_STATUS: i32 = 124;
+/// exit status of a step failed to start, e.g. SSH connection failed, same as `ssh`
+const ERROR_STATUS: i32 = 255;
 
 impl Repository {
     pub async fn execute(&self, triggered_info: &TriggeredInfo) -> Result<()> {
-        let mut action_result = Vec::new();
         let steps_name = triggered_info.steps_name.as_deref();
-        for i in self
+        let deadline = self
+            .get_steps_timeout(steps_name)
+            .map(|t| Instant::now() + Duration::from_secs(t));
+        let steps = self
             .get_steps(steps_name)
-            .ok_or_else(|| anyhow!("missing steps or steps name is invalid"))?
-        {
-            let result = i.execute(&CONFIG, self, triggered_info).await?;
-            let is_success = result.success();
-            action_result.push(result);
-            if !is_success {
-                break;
+            .ok_or_else(|| anyhow!("missing steps or steps name is invalid"))?;
+        let needs = dependencies(steps)?;
+        // SSH sessions are reused by the steps of this run
+        let pool = SessionPool::new();
+        let _keepalive = pool.keepalive();
+        // the running SSH steps are killed if this run is finished or aborted
+        let _cancel = pool.cancel_on_drop();
+        let mut results: Vec<Option<StepResult>> = steps.iter().map(|_| None).collect();
+        let mut started = vec![false; steps.len()];
+        let mut done = vec![false; steps.len()];
+        // the step or any of its dependencies failed
+        let mut failed = vec![false; steps.len()];
+        let mut running = FuturesUnordered::new();
+        loop {
+            // start the steps whose dependencies are done, until nothing changes
+            let mut changed = true;
+            while changed {
+                changed = false;
+                for (index, step) in steps.iter().enumerate() {
+                    if started[index] || !needs[index].iter().all(|&n| done[n]) {
+                        continue;
+                    }
+                    started[index] = true;
+                    let needs_failed = needs[index].iter().any(|&n| failed[n]);
+                    if step.should_run(&CONFIG, self, triggered_info, needs_failed) {
+                        let pool = &pool;
+                        running.push(async move {
+                            let result = step
+                                .run(&CONFIG, self, triggered_info, pool, deadline)
+                                .await;
+                            (index, needs_failed, result)
+                        });
+                    } else {
+                        done[index] = true;
+                        failed[index] = needs_failed;
+                        changed = true;
+                    }
+                }
             }
+            let (index, needs_failed, result) = match running.next().await {
+                Some(r) => r,
+                None => break,
+            };
+            done[index] = true;
+            failed[index] = needs_failed || !result.success();
+            results[index] = Some(result);
         }
-        let status = (&action_result)
-            .last()
-            .map(|i| i.status)
-            .unwrap_or_else(|| 0);
+        // results are in the order of steps, skipped steps are omitted
+        let action_result: Vec<StepResult> = results.into_iter().flatten().collect();
+        let status = action_result
+            .iter()
+            .find(|r| !r.success())
+            .map_or(0, |r| r.status);
         let result = StepsResult {
             status,
             action_result,
@@ -56,6 +121,32 @@ impl Repository {
     }
 }
 
+/// indexes of the dependencies of each step, a step without `needs` depends on the previous one
+fn dependencies(steps: &[Step]) -> Result<Vec<Vec<usize>>> {
+    let mut ids = HashMap::new();
+    let mut dependencies = Vec::new();
+    for (index, step) in steps.iter().enumerate() {
+        let needs = match &step.needs {
+            Some(needs) => needs
+                .iter()
+                .map(|n| {
+                    ids.get(n.as_str())
+                        .copied()
+                        .ok_or_else(|| anyhow!("step \"{}\" is not defined before it is needed", n))
+                })
+                .collect::<Result<_>>()?,
+            None => index.checked_sub(1).into_iter().collect(),
+        };
+        dependencies.push(needs);
+        if let Some(id) = &step.id {
+            if ids.insert(id.as_str(), index).is_some() {
+                bail!("duplicate step id \"{}\"", id);
+            }
+        }
+    }
+    Ok(dependencies)
+}
+
 pub struct StepsResult {
     status: i32,
     action_result: Vec<StepResult>,
@@ -85,6 +176,18 @@ pub struct StepResult {
     description: Option<String>,
     stdout: Option<Vec<u8>>,
     stderr: Option<Vec<u8>>,
+    /// why the step failed to start or finish
+    error: Option<String>,
+    /// stdout and stderr in the order they are read, with timestamps
+    output: Option<Vec<u8>>,
+    timed_out: bool,
+    /// number of attempts, the previous failed attempts are kept in `previous_attempts`
+    attempts: u32,
+    previous_attempts: Vec<StepResult>,
+    /// set if the step runs on a member of a host group
+    host: Option<String>,
+    /// results of the members if the step targets a host group
+    hosts: Vec<StepResult>,
 }
 
 impl StepResult {
@@ -99,14 +202,111 @@ impl StepResult {
             description,
             stdout,
             stderr,
+            error: None,
+            output: None,
+            timed_out: false,
+            attempts: 1,
+            previous_attempts: Vec::new(),
+            host: None,
+            hosts: Vec::new(),
+        }
+    }
+
+    /// output is what was read before the step was killed
+    pub fn timed_out(
+        description: Option<String>,
+        stdout: Option<Vec<u8>>,
+        stderr: Option<Vec<u8>>,
+    ) -> StepResult {
+        StepResult {
+            status: TIMED_OUT_STATUS,
+            description,
+            stdout,
+            stderr,
+            error: None,
+            output: None,
+            timed_out: true,
+            attempts: 1,
+            previous_attempts: Vec::new(),
+            host: None,
+            hosts: Vec::new(),
+        }
+    }
+
+    /// the step failed to start or finish, e.g. SSH connection failed, command not found
+    fn error(description: Option<String>, error: &anyhow::Error) -> StepResult {
+        StepResult {
+            error: Some(format!("{:#}", error)),
+            ..StepResult::new(ERROR_STATUS, description, None, None)
+        }
+    }
+
+    fn with_output(mut self, output: Vec<u8>) -> StepResult {
+        self.output = Some(output);
+        self
+    }
+
+    /// results of the members of a host group, failed if any of them failed
+    fn group(description: Option<String>, hosts: Vec<StepResult>) -> StepResult {
+        let status = hosts.iter().find(|r| !r.success()).map_or(0, |r| r.status);
+        StepResult {
+            hosts,
+            ..StepResult::new(status, description, None, None)
         }
     }
 
     fn success(&self) -> bool {
-        self.status == 0
+        self.status == 0 && !self.timed_out
     }
 
     async fn save_to_file(&self, config: &Config, parent_dir: &Path) -> Result<StepLog> {
+        let mut step_log = self.save_attempts(config, parent_dir).await?;
+        for (index, result) in self.hosts.iter().enumerate() {
+            let log = result
+                .save_attempts(config, &parent_dir.join(format!("host{}", index + 1)))
+                .await?;
+            step_log.hosts.push(log);
+        }
+        Ok(step_log)
+    }
+
+    async fn save_attempts(&self, config: &Config, parent_dir: &Path) -> Result<StepLog> {
+        let (stdout, stderr, output) = self.save_output(config, parent_dir).await?;
+        let mut previous_attempts = Vec::new();
+        for (index, result) in self.previous_attempts.iter().enumerate() {
+            let (stdout, stderr, output) = result
+                .save_output(config, &parent_dir.join(format!("attempt{}", index + 1)))
+                .await?;
+            previous_attempts.push(AttemptLog {
+                status: result.status,
+                stdout,
+                stderr,
+                output,
+                error: result.error.clone(),
+                timed_out: result.timed_out,
+            });
+        }
+        let step_log = StepLog {
+            description: self.description.clone(),
+            stdout,
+            stderr,
+            output,
+            error: self.error.clone(),
+            timed_out: self.timed_out,
+            attempts: self.attempts,
+            previous_attempts,
+            host: self.host.clone(),
+            hosts: Vec::new(),
+        };
+        Ok(step_log)
+    }
+
+    /// write stdout, stderr and the combined output to files, return their urls
+    async fn save_output(
+        &self,
+        config: &Config,
+        parent_dir: &Path,
+    ) -> Result<(Option<String>, Option<String>, Option<String>)> {
         let dir = config.work_dir.as_deref().unwrap_or("/tmp");
         let dir = Path::new(&dir).join("cache").join("logs").join(parent_dir);
         if !dir.exists() {
@@ -138,32 +338,177 @@ impl StepResult {
             write_and_get_url(&url, parent_dir, &dir, "1", self.stdout.as_ref()).await?;
         let stderr_url =
             write_and_get_url(&url, parent_dir, &dir, "2", self.stderr.as_ref()).await?;
-        let step_log = StepLog {
-            description: self.description.clone(),
-            stdout: stdout_url,
-            stderr: stderr_url,
-        };
-        Ok(step_log)
+        let output_url =
+            write_and_get_url(&url, parent_dir, &dir, "3", self.output.as_ref()).await?;
+        Ok((stdout_url, stderr_url, output_url))
     }
 }
 
 impl Step {
+    /// check `when` of this step, `failed` is whether any of the previous steps failed
+    fn should_run(
+        &self,
+        config: &Config,
+        repository: &Repository,
+        triggered_info: &TriggeredInfo,
+        failed: bool,
+    ) -> bool {
+        self.when.is_match(failed, |name| match name {
+            "repository" => Some(triggered_info.repository.as_str()),
+            "branch" => triggered_info.branch.as_deref(),
+            "tag" => triggered_info.tag.as_deref(),
+            "event" => triggered_info.event.as_deref(),
+            "steps_name" => triggered_info.steps_name.as_deref(),
+            name => {
+                let name = name.strip_prefix("env.")?;
+                self.environment(config, repository, triggered_info)
+                    .into_iter()
+                    .filter(|(k, _)| *k == name)
+                    .last()
+                    .map(|(_, v)| v)
+            }
+        })
+    }
+
+    /// run on the host, or on the members of the host group by `strategy`
+    async fn run(
+        &self,
+        config: &Config,
+        repository: &Repository,
+        triggered_info: &TriggeredInfo,
+        pool: &SessionPool,
+        deadline: Option<Instant>,
+    ) -> StepResult {
+        let group = self
+            .host
+            .as_ref()
+            .filter(|h| !config.host.contains_key(*h))
+            .and_then(|h| config.host_group.as_ref()?.get(h));
+        let group = match group {
+            Some(group) => group,
+            None => {
+                return self
+                    .execute_with_retry(
+                        config,
+                        repository,
+                        triggered_info,
+                        pool,
+                        self.host.as_deref(),
+                        deadline,
+                    )
+                    .await;
+            }
+        };
+        let mut hosts = Vec::new();
+        for batch in group.chunks(self.strategy.batch_size(group.len())) {
+            let results = join_all(batch.iter().map(|host| async move {
+                let mut result = self
+                    .execute_with_retry(
+                        config,
+                        repository,
+                        triggered_info,
+                        pool,
+                        Some(host),
+                        deadline,
+                    )
+                    .await;
+                result.host = Some(host.clone());
+                result
+            }))
+            .await;
+            hosts.extend(results);
+            if hosts.iter().any(|r| !r.success()) {
+                break;
+            }
+        }
+        StepResult::group(self.description.clone(), hosts)
+    }
+
+    /// execute and retry according to `retry`, other errors than `ConnectError` are not retried,
+    /// an error of the last attempt is turned into a failed result
+    async fn execute_with_retry(
+        &self,
+        config: &Config,
+        repository: &Repository,
+        triggered_info: &TriggeredInfo,
+        pool: &SessionPool,
+        host: Option<&str>,
+        deadline: Option<Instant>,
+    ) -> StepResult {
+        let attempts = self.retry.as_ref().map_or(1, |r| r.attempts.max(1));
+        let retry_on = |on| self.retry.as_ref().is_some_and(|r| r.is_retry_on(on));
+        let mut delay = self.retry.as_ref().map_or(0.0, |r| r.delay);
+        let mut previous_attempts = Vec::new();
+        let mut attempt = 1;
+        loop {
+            let timeout = self.timeout(config, deadline);
+            let is_last = attempt == attempts;
+            match self
+                .execute(config, repository, triggered_info, pool, host, timeout)
+                .await
+            {
+                Ok(result) if !result.success() && !is_last && retry_on(RetryOn::NonzeroExit) => {
+                    previous_attempts.push(result);
+                }
+                Ok(mut result) => {
+                    result.attempts = attempt;
+                    result.previous_attempts = previous_attempts;
+                    return result;
+                }
+                Err(e) if e.is::<ConnectError>() && !is_last && retry_on(RetryOn::ConnectError) => {
+                    warn!("step attempt {} error: {}", attempt, e);
+                }
+                Err(e) => {
+                    let mut result = StepResult::error(self.description.clone(), &e);
+                    result.attempts = attempt;
+                    result.previous_attempts = previous_attempts;
+                    return result;
+                }
+            }
+            // a negative or overflowed delay is ignored
+            let wait = Duration::try_from_secs_f64(delay).unwrap_or_else(|e| {
+                warn!("invalid retry delay {}: {}", delay, e);
+                Duration::ZERO
+            });
+            tokio::time::sleep(wait).await;
+            delay *= self.retry.as_ref().map_or(1.0, |r| r.backoff);
+            attempt += 1;
+        }
+    }
+
+    /// `timeout` of this step or the global default, limited by `deadline` of all steps
+    fn timeout(&self, config: &Config, deadline: Option<Instant>) -> Option<Duration> {
+        let timeout = self.timeout.or(config.timeout).map(Duration::from_secs);
+        match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                Some(timeout.map_or(remaining, |t| t.min(remaining)))
+            }
+            None => timeout,
+        }
+    }
+
     async fn execute(
         &self,
         config: &Config,
         repository: &Repository,
         triggered_info: &TriggeredInfo,
+        pool: &SessionPool,
+        host: Option<&str>,
+        timeout: Option<Duration>,
     ) -> Result<StepResult> {
-        use std::process::Command;
+        use tokio::process::Command;
 
         let envs = self.environment(config, repository, triggered_info);
         let work_dir = config.work_dir.as_deref().unwrap_or("/tmp");
-        match &self.host {
+        match host {
             Some(host) => {
-                let host = config.host.get(host).context("invalid host")?.clone();
+                let route = ssh::route(config, host)?;
                 let _self = self.clone();
+                let pool = pool.clone();
+                let name = host.to_string();
                 let work_dir = work_dir.to_string();
-                unblock(move || _self.ssh(&host, &work_dir)).await
+                unblock(move || _self.ssh(&pool, &name, &route, &work_dir, timeout)).await
             }
             None => {
                 let (mut cmd, args) = match &self.action {
@@ -185,10 +530,56 @@ impl Step {
                     cmd.current_dir(current_dir);
                 }
                 cmd.envs(envs);
-                let output = unblock(move || cmd.output()).await?;
-                let mut step_result: StepResult = output.into();
-                step_result.description = self.description.clone();
-                Ok(step_result)
+                cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+                // run in a new process group, so that all its children can be killed
+                cmd.process_group(0);
+                let mut child = cmd
+                    .spawn()
+                    .with_context(|| format!("failed to run {:?}", cmd.as_std().get_program()))?;
+                // the process group is killed if the step is timed out or the run is cancelled
+                let group = ProcessGroup(child.id());
+                let mut output = Output::new();
+                let mut stdout_pipe = child.stdout.take().context("missing stdout")?;
+                let mut stderr_pipe = child.stderr.take().context("missing stderr")?;
+                let run = async {
+                    let mut stdout_chunk = [0; 8192];
+                    let mut stderr_chunk = [0; 8192];
+                    let (mut stdout_eof, mut stderr_eof) = (false, false);
+                    while !(stdout_eof && stderr_eof) {
+                        tokio::select! {
+                            n = stdout_pipe.read(&mut stdout_chunk), if !stdout_eof => match n? {
+                                0 => stdout_eof = true,
+                                n => output.push(Stream::Stdout, &stdout_chunk[..n]),
+                            },
+                            n = stderr_pipe.read(&mut stderr_chunk), if !stderr_eof => match n? {
+                                0 => stderr_eof = true,
+                                n => output.push(Stream::Stderr, &stderr_chunk[..n]),
+                            },
+                        }
+                    }
+                    child.wait().await
+                };
+                let status = match timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, run).await.ok(),
+                    None => Some(run.await),
+                };
+                let (stdout, stderr, combined) = output.into_parts();
+                let step_result = match status {
+                    Some(status) => {
+                        group.release();
+                        StepResult::new(
+                            status?.code().unwrap_or(0),
+                            self.description.clone(),
+                            Some(stdout),
+                            Some(stderr),
+                        )
+                    }
+                    None => {
+                        drop(group);
+                        StepResult::timed_out(self.description.clone(), Some(stdout), Some(stderr))
+                    }
+                };
+                Ok(step_result.with_output(combined))
             }
         }
     }
@@ -211,13 +602,22 @@ impl Step {
     }
 }
 
-impl From<Output> for StepResult {
-    fn from(output: Output) -> StepResult {
-        StepResult {
-            status: output.status.code().unwrap_or(0),
-            description: None,
-            stdout: Some(output.stdout),
-            stderr: Some(output.stderr),
+/// kill the process group on drop unless it is released,
+/// processes left in background by a finished step are kept
+struct ProcessGroup(Option<u32>);
+
+impl ProcessGroup {
+    fn release(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for ProcessGroup {
+    fn drop(&mut self) {
+        if let Some(pgid) = self.0 {
+            unsafe {
+                libc::killpg(pgid as libc::pid_t, libc::SIGKILL);
+            }
         }
     }
 }
@@ -227,6 +627,23 @@ pub struct StepLog {
     description: Option<String>,
     stdout: Option<String>,
     stderr: Option<String>,
+    output: Option<String>,
+    error: Option<String>,
+    timed_out: bool,
+    attempts: u32,
+    previous_attempts: Vec<AttemptLog>,
+    host: Option<String>,
+    hosts: Vec<StepLog>,
+}
+
+#[derive(Serialize)]
+pub struct AttemptLog {
+    status: i32,
+    stdout: Option<String>,
+    stderr: Option<String>,
+    output: Option<String>,
+    error: Option<String>,
+    timed_out: bool,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -0,0 +1,72 @@
+use std::time::{Duration, Instant};
+
+/// stdout and stderr of a step, chunks are also kept in the order they are read,
+/// so that the combined output reads like the terminal output
+pub struct Output {
+    start: Instant,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    chunks: Vec<Chunk>,
+}
+
+struct Chunk {
+    elapsed: Duration,
+    stream: Stream,
+    data: Vec<u8>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Output {
+    pub fn new() -> Output {
+        Output {
+            start: Instant::now(),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+            chunks: Vec::new(),
+        }
+    }
+
+    pub fn push(&mut self, stream: Stream, data: &[u8]) {
+        match stream {
+            Stream::Stdout => self.stdout.extend_from_slice(data),
+            Stream::Stderr => self.stderr.extend_from_slice(data),
+        }
+        self.chunks.push(Chunk {
+            elapsed: self.start.elapsed(),
+            stream,
+            data: data.to_vec(),
+        });
+    }
+
+    /// stdout, stderr and the combined output, each line of the combined output is prefixed
+    /// with the seconds since the start and `E` for stderr, e.g. `[   1.024] E error: ...`
+    pub fn into_parts(self) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+        let mut combined = Vec::new();
+        let mut last = None;
+        for chunk in &self.chunks {
+            for line in chunk.data.split_inclusive(|b| *b == b'\n') {
+                let line_start = combined.last().is_none_or(|b| *b == b'\n');
+                // an unfinished line is broken by the output of the other stream
+                if !line_start && last != Some(chunk.stream) {
+                    combined.push(b'\n');
+                }
+                if combined.last().is_none_or(|b| *b == b'\n') {
+                    let marker = match chunk.stream {
+                        Stream::Stdout => ' ',
+                        Stream::Stderr => 'E',
+                    };
+                    let prefix = format!("[{:8.3}] {} ", chunk.elapsed.as_secs_f64(), marker);
+                    combined.extend_from_slice(prefix.as_bytes());
+                }
+                combined.extend_from_slice(line);
+                last = Some(chunk.stream);
+            }
+        }
+        (self.stdout, self.stderr, combined)
+    }
+}
@@ -0,0 +1,175 @@
+use std::{
+    collections::HashMap,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use blocking::unblock;
+use log::warn;
+use ssh2::{Channel, Session};
+use tokio::task::JoinHandle;
+
+use super::{
+    queue::{self, Running},
+    ssh::{connect, limit, ConnectError},
+};
+use crate::config::Host;
+
+/// interval of keepalive messages sent on idle sessions
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+type Idle = Arc<Mutex<HashMap<String, Vec<Session>>>>;
+
+/// SSH sessions of a run keyed by host name, a session is borrowed by one step at a time,
+/// so parallel steps on the same host get their own sessions
+#[derive(Clone)]
+pub struct SessionPool {
+    idle: Idle,
+    /// the run is cancelled, running SSH steps are killed
+    cancelled: Arc<AtomicBool>,
+    /// cloned into the threads of SSH steps, the next run of the queue waits for them
+    _running: Option<Running>,
+}
+
+impl SessionPool {
+    /// a pool of the current job of the run queue
+    pub fn new() -> SessionPool {
+        SessionPool {
+            idle: Idle::default(),
+            cancelled: Arc::default(),
+            _running: queue::running(),
+        }
+    }
+
+    /// an idle session of `name`, or a new one connected through `route` before `deadline`,
+    /// with a channel opened on it, an idle session is dropped if it fails to send a keepalive
+    /// message or to open the channel, since a half dead connection may still send keepalive
+    /// messages, a failure of a new session is a `ConnectError`
+    pub fn get(
+        &self,
+        name: &str,
+        route: &[Host],
+        deadline: Option<Instant>,
+    ) -> Result<(PooledSession, Channel)> {
+        loop {
+            let session = lock(&self.idle).get_mut(name).and_then(Vec::pop);
+            let session = match session {
+                Some(session) => session,
+                None => {
+                    let (session, channel) = connect(route, deadline)
+                        .and_then(|session| {
+                            let channel = session.channel_session()?;
+                            Ok((session, channel))
+                        })
+                        .context(ConnectError)?;
+                    return Ok((self.pooled(name, session), channel));
+                }
+            };
+            limit(&session, deadline)?;
+            let channel = session
+                .keepalive_send()
+                .and_then(|_| session.channel_session());
+            match channel {
+                Ok(channel) => return Ok((self.pooled(name, session), channel)),
+                Err(e) => warn!("drop broken SSH session of \"{}\": {}", name, e),
+            }
+        }
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    /// cancel the run when the returned guard is dropped, e.g. the run is aborted by
+    /// `cancel_previous`, SSH steps are run in threads which are not stopped by aborting
+    pub fn cancel_on_drop(&self) -> CancelOnDrop {
+        CancelOnDrop(self.cancelled.clone())
+    }
+
+    fn pooled(&self, name: &str, session: Session) -> PooledSession {
+        PooledSession {
+            name: name.to_string(),
+            session,
+            pool: self.clone(),
+            released: false,
+        }
+    }
+
+    /// send keepalive messages on idle sessions until the returned guard is dropped
+    pub fn keepalive(&self) -> Keepalive {
+        let idle = self.idle.clone();
+        Keepalive(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(KEEPALIVE_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let idle = idle.clone();
+                unblock(move || {
+                    for sessions in lock(&idle).values_mut() {
+                        sessions.retain(|s| s.keepalive_send().is_ok());
+                    }
+                })
+                .await;
+            }
+        }))
+    }
+}
+
+fn lock(idle: &Idle) -> std::sync::MutexGuard<'_, HashMap<String, Vec<Session>>> {
+    idle.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// a borrowed session, it is dropped unless released back to the pool,
+/// so that a session in an unknown state is not reused
+pub struct PooledSession {
+    name: String,
+    session: Session,
+    pool: SessionPool,
+    released: bool,
+}
+
+impl PooledSession {
+    pub fn release(mut self) {
+        self.released = true;
+    }
+}
+
+impl Deref for PooledSession {
+    type Target = Session;
+
+    fn deref(&self) -> &Session {
+        &self.session
+    }
+}
+
+impl Drop for PooledSession {
+    fn drop(&mut self) {
+        if self.released {
+            lock(&self.pool.idle)
+                .entry(std::mem::take(&mut self.name))
+                .or_default()
+                .push(self.session.clone());
+        }
+    }
+}
+
+pub struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+pub struct Keepalive(JoinHandle<()>);
+
+impl Drop for Keepalive {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
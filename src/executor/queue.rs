@@ -0,0 +1,142 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use once_cell::sync::Lazy;
+use tokio::{runtime::Handle, task::JoinHandle};
+
+use crate::config::ConcurrencyPolicy;
+
+type Job = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// run queues keyed by repository entry (and steps name)
+static QUEUES: Lazy<Mutex<HashMap<String, RunQueue>>> = Lazy::new(Default::default);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static PENDING: AtomicUsize = AtomicUsize::new(0);
+
+tokio::task_local! {
+    static RUNNING: Running;
+}
+
+/// held by a job and the threads started by it, the next job of the same key is started
+/// after all of them are dropped, a thread is not stopped by aborting the job
+#[derive(Clone)]
+pub struct Running {
+    _finish: Arc<Finish>,
+}
+
+/// `Running` of the current job
+pub fn running() -> Option<Running> {
+    RUNNING.try_with(Clone::clone).ok()
+}
+
+#[derive(Default)]
+struct RunQueue {
+    running: Option<(u64, JoinHandle<()>)>,
+    pending: VecDeque<Job>,
+}
+
+impl RunQueue {
+    fn push(&mut self, job: Job) {
+        PENDING.fetch_add(1, Ordering::Relaxed);
+        self.pending.push_back(job);
+    }
+
+    fn pop(&mut self) -> Option<Job> {
+        let job = self.pending.pop_front()?;
+        PENDING.fetch_sub(1, Ordering::Relaxed);
+        Some(job)
+    }
+
+    fn clear(&mut self) {
+        PENDING.fetch_sub(self.pending.len(), Ordering::Relaxed);
+        self.pending.clear();
+    }
+}
+
+/// number of jobs waiting for the running job of the same key
+pub fn pending() -> usize {
+    PENDING.load(Ordering::Relaxed)
+}
+
+/// run `job` after the running job of the same `key` according to `policy`
+pub fn submit<F>(key: String, policy: ConcurrencyPolicy, job: F)
+where
+    F: Future<Output = ()> + Send + 'static,
+{
+    let job = Box::pin(job);
+    let mut queues = QUEUES.lock().unwrap();
+    let queue = queues.entry(key.clone()).or_default();
+    if let Some((_, running)) = &queue.running {
+        match policy {
+            ConcurrencyPolicy::Queue => {
+                queue.push(job);
+                return;
+            }
+            ConcurrencyPolicy::Coalesce => {
+                queue.clear();
+                queue.push(job);
+                return;
+            }
+            ConcurrencyPolicy::CancelPrevious => {
+                // started once the threads of the aborted job are done
+                running.abort();
+                queue.clear();
+                queue.push(job);
+                return;
+            }
+        }
+    }
+    start(key, queue, job, Handle::current());
+}
+
+fn start(key: String, queue: &mut RunQueue, job: Job, runtime: Handle) {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    // the next job is started even if this one panics
+    let finish = Running {
+        _finish: Arc::new(Finish {
+            key,
+            id,
+            runtime: runtime.clone(),
+        }),
+    };
+    let handle = runtime.spawn(RUNNING.scope(finish, job));
+    queue.running = Some((id, handle));
+}
+
+/// call `finish` on drop, it may be dropped by a thread outside of the runtime
+struct Finish {
+    key: String,
+    id: u64,
+    runtime: Handle,
+}
+
+impl Drop for Finish {
+    fn drop(&mut self) {
+        finish(std::mem::take(&mut self.key), self.id, &self.runtime);
+    }
+}
+
+/// start the next pending job of `key` if job `id` is still the running one
+fn finish(key: String, id: u64, runtime: &Handle) {
+    let mut queues = QUEUES.lock().unwrap();
+    let queue = match queues.get_mut(&key) {
+        Some(q) => q,
+        None => return,
+    };
+    if queue.running.as_ref().map(|(i, _)| *i) != Some(id) {
+        return;
+    }
+    match queue.pop() {
+        Some(job) => start(key, queue, job, runtime.clone()),
+        None => {
+            queues.remove(&key);
+        }
+    }
+}
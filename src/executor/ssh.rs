@@ -1,43 +1,51 @@
 use std::{
     borrow::Cow,
-    fs::File,
-    io::{copy, Read},
-    net::TcpStream,
-    path::Path,
+    fmt::{self, Display, Formatter},
+    fs::{create_dir_all, File, OpenOptions},
+    io::{copy, ErrorKind, Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+    os::unix::{io::AsRawFd, net::UnixStream},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, bail, Result};
-use ssh2::Session;
+use log::warn;
+use openssl::base64::{decode_block, encode_block};
+use ssh2::{Channel, CheckResult, ErrorCode, HashType, HostKeyType, KnownHostFileKind, Session};
 
+use super::{
+    output::{Output, Stream},
+    pool::{SessionPool, KEEPALIVE_INTERVAL},
+};
 use crate::{
-    config::{Action, Host, Step},
+    config::{Action, Config, Host, HostKeyCheck, Secret, Step},
     executor::StepResult,
 };
 
 impl Step {
-    pub fn ssh(&self, host: &Host, work_dir: &str) -> Result<StepResult> {
-        let stream = TcpStream::connect((host.hostname.as_str(), host.port.unwrap_or(22)))?;
-        let mut session = Session::new()?;
-        session.set_tcp_stream(stream);
-        session.handshake()?;
-        let private_key = {
-            let home = std::env::var("HOME")
-                .map_err(|_| anyhow!("\"HOME\" environment variable is required"))?;
-            let home = Path::new(&home);
-            let ed25519 = home.join(".ssh/id_ed25519");
-            let rsa = home.join(".ssh/id_rsa");
-            if ed25519.exists() {
-                ed25519
-            } else if rsa.exists() {
-                rsa
-            } else {
-                bail!("missing private key");
-            }
-        };
-        session.userauth_pubkey_file(&host.user, None, &private_key, None)?;
+    /// run on host `name` with a session borrowed from `pool`,
+    /// `route` is the host preceded by its jump hosts, see `route`
+    pub fn ssh(
+        &self,
+        pool: &SessionPool,
+        name: &str,
+        route: &[Host],
+        work_dir: &str,
+        timeout: Option<Duration>,
+    ) -> Result<StepResult> {
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let (session, mut channel) = pool.get(name, route, deadline)?;
+        if pool.is_cancelled() {
+            bail!("cancelled");
+        }
+        limit(&session, deadline)?;
 
         let remote_filename = format!("/tmp/delivery_station_{}", crate::tmp_filename(12));
-        let (name, args) = match &self.action {
+        // the remote shell is a session leader, its pid is the process group id
+        let pid_filename = format!("{}.pid", remote_filename);
+        let (command, args) = match &self.action {
             Action::Script { name } => {
                 let script_name = self.get_script_fullname(work_dir, name.get_name())?;
                 let mut file = File::open(script_name)?;
@@ -50,32 +58,480 @@ impl Step {
             Action::Command { command } => (command.get_name(), command.get_args()),
         };
         let remote_cmd = match args {
-            None => Cow::Borrowed(name),
-            Some(args) => format!("{} \"{}\"", name, args.join("\" \"")).into(),
+            None => Cow::Borrowed(command),
+            Some(args) => format!("{} \"{}\"", command, args.join("\" \"")).into(),
         };
-        let mut channel = session.channel_session()?;
+        let remote_cmd = format!("echo $$ > {}; {}", pid_filename, remote_cmd);
         channel.exec(&remote_cmd)?;
 
-        let mut stdout = String::new();
-        channel.read_to_string(&mut stdout)?;
-        let mut stderr = String::new();
-        let mut stderr_stream = channel.stderr();
-        stderr_stream.read_to_string(&mut stderr)?;
-        channel.wait_close()?;
-        let status = channel.exit_status()?;
+        let mut output = Output::new();
+        // both streams are drained, a full window of one stream would block the other,
+        // the deadline is checked by `read_output` instead
+        session.set_timeout(0);
+        session.set_blocking(false);
+        let interrupt = read_output(&session, &mut channel, &mut output, deadline, pool);
+        session.set_blocking(true);
+        let (stdout, stderr, combined) = output.into_parts();
+
+        let mut exited = false;
+        let result = match interrupt {
+            Ok(None) => match channel.wait_close().and_then(|_| channel.exit_status()) {
+                Ok(status) => {
+                    exited = true;
+                    Ok(StepResult::new(
+                        status,
+                        self.description.clone(),
+                        Some(stdout),
+                        Some(stderr),
+                    ))
+                }
+                Err(e) => Err(e.into()),
+            },
+            Ok(Some(Interrupt::TimedOut)) => Ok(StepResult::timed_out(
+                self.description.clone(),
+                Some(stdout),
+                Some(stderr),
+            )),
+            Ok(Some(Interrupt::Cancelled)) => Err(anyhow!("cancelled")),
+            Err(e) => Err(e),
+        };
 
+        let mut cleanup = format!("rm -f {}", pid_filename);
         if let Action::Script { .. } = &self.action {
-            let mut channel = session.channel_session()?;
-            channel.exec(&format!("rm {}", remote_filename))?;
-            channel.close()?;
-        }
-
-        let result = StepResult::new(
-            status,
-            self.description.clone(),
-            Some(stdout.as_bytes().to_vec()),
-            Some(stderr.as_bytes().to_vec()),
-        );
-        Ok(result)
+            cleanup = format!("{} {}", cleanup, remote_filename);
+        }
+        if !exited {
+            // kill the remote process group, it is not killed by closing the channel,
+            // and it must not run alongside a retry
+            if let Err(e) = channel.close() {
+                warn!("close channel on \"{}\" error: {}", name, e);
+            }
+            cleanup = format!("kill -s KILL -- -$(cat {}); {}", pid_filename, cleanup);
+        }
+        session.set_timeout(CLEANUP_TIMEOUT.as_millis() as u32);
+        match session
+            .channel_session()
+            .and_then(|mut channel| channel.exec(&cleanup).and_then(|_| channel.close()))
+        {
+            Ok(()) => session.release(),
+            Err(e) => warn!("clean up on \"{}\" error: {}", name, e),
+        }
+
+        result.map(|r| r.with_output(combined))
+    }
+}
+
+/// failed to connect, handshake or authenticate, the command is not run,
+/// see `RetryOn::ConnectError`
+#[derive(Debug)]
+pub struct ConnectError;
+
+impl Display for ConnectError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "failed to connect")
+    }
+}
+
+const LIBSSH2_ERROR_EAGAIN: i32 = -37;
+/// timeout of cleaning up after the command, it runs after the deadline of a timed out step
+const CLEANUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// `name` and its jump hosts, the outermost jump host first
+pub fn route(config: &Config, name: &str) -> Result<Vec<Host>> {
+    let mut route = Vec::new();
+    let mut name = Some(name);
+    while let Some(n) = name {
+        if route.len() > config.host.len() {
+            bail!("proxy_jump of \"{}\" is a loop", n);
+        }
+        let host = config
+            .host
+            .get(n)
+            .ok_or_else(|| anyhow!("invalid host \"{}\"", n))?;
+        route.push(host.clone());
+        name = host.proxy_jump.as_deref();
+    }
+    route.reverse();
+    Ok(route)
+}
+
+/// connect to the last host of `route` through the previous ones before `deadline`
+pub fn connect(route: &[Host], deadline: Option<Instant>) -> Result<Session> {
+    let mut previous: Option<Session> = None;
+    for host in route {
+        let port = host.port.unwrap_or(22);
+        let mut session = Session::new()?;
+        match previous.take() {
+            Some(jump) => {
+                limit(&jump, deadline)?;
+                session.set_tcp_stream(tunnel(jump, &host.hostname, port)?)
+            }
+            None => session.set_tcp_stream(tcp_connect(&host.hostname, port, deadline)?),
+        }
+        limit(&session, deadline)?;
+        session.handshake()?;
+        verify_host_key(&session, host)?;
+        limit(&session, deadline)?;
+        authenticate(&session, host)?;
+        session.set_timeout(0);
+        session.set_keepalive(true, KEEPALIVE_INTERVAL.as_secs() as u32);
+        previous = Some(session);
+    }
+    previous.ok_or_else(|| anyhow!("missing host"))
+}
+
+/// limit blocking calls of `session` to the time left before `deadline`, no limit if missing
+pub fn limit(session: &Session, deadline: Option<Instant>) -> Result<()> {
+    let timeout = match deadline {
+        Some(deadline) => {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                bail!("timed out");
+            }
+            // 0 means no limit
+            remaining.as_millis().clamp(1, u32::MAX as u128) as u32
+        }
+        None => 0,
+    };
+    session.set_timeout(timeout);
+    Ok(())
+}
+
+fn tcp_connect(hostname: &str, port: u16, deadline: Option<Instant>) -> Result<TcpStream> {
+    let deadline = match deadline {
+        Some(deadline) => deadline,
+        None => return Ok(TcpStream::connect((hostname, port))?),
+    };
+    let mut error = None;
+    for addr in (hostname, port).to_socket_addrs()? {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            bail!("timed out");
+        }
+        match TcpStream::connect_timeout(&addr, remaining) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => error = Some(e),
+        }
+    }
+    match error {
+        Some(e) => Err(e.into()),
+        None => bail!("failed to resolve \"{}\"", hostname),
+    }
+}
+
+/// open a `direct-tcpip` channel to `hostname:port` through `jump`, the returned stream is
+/// connected to the channel by a thread, which exits once either side is closed
+fn tunnel(jump: Session, hostname: &str, port: u16) -> Result<UnixStream> {
+    let channel = jump.channel_direct_tcpip(hostname, port, None)?;
+    let (local, remote) = UnixStream::pair()?;
+    remote.set_nonblocking(true)?;
+    // the jump session is only used by this thread from now on, a blocking read would hold
+    // the session and block writing
+    jump.set_blocking(false);
+    std::thread::spawn(move || {
+        if let Err(e) = pump(&jump, channel, remote) {
+            warn!("ssh tunnel error: {}", e);
+        }
+    });
+    Ok(local)
+}
+
+/// copy data between `channel` and `stream` until either side is closed
+fn pump(jump: &Session, mut channel: Channel, mut stream: UnixStream) -> Result<()> {
+    let jump_fd = jump.as_raw_fd();
+    let mut chunk = [0; 16384];
+    // data read from one side but not written to the other yet
+    let mut outgoing = Vec::new();
+    let mut incoming = Vec::new();
+    loop {
+        let mut progressed = false;
+        if outgoing.is_empty() {
+            match stream.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => {
+                    outgoing.extend_from_slice(&chunk[..n]);
+                    progressed = true;
+                }
+                Err(e) if is_retryable(&e) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if !outgoing.is_empty() {
+            match channel.write(&outgoing) {
+                Ok(n) => {
+                    outgoing.drain(..n);
+                    progressed = true;
+                }
+                Err(e) if is_retryable(&e) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if incoming.is_empty() {
+            match channel.read(&mut chunk) {
+                Ok(0) if channel.eof() => break,
+                Ok(0) => {}
+                Ok(n) => {
+                    incoming.extend_from_slice(&chunk[..n]);
+                    progressed = true;
+                }
+                Err(e) if is_retryable(&e) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if !incoming.is_empty() {
+            match stream.write(&incoming) {
+                Ok(n) => {
+                    incoming.drain(..n);
+                    progressed = true;
+                }
+                Err(e) if is_retryable(&e) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if !progressed {
+            // wait for either side, libssh2 may buffer data, so do not wait too long
+            let mut fds = [
+                libc::pollfd {
+                    fd: stream.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+                libc::pollfd {
+                    fd: jump_fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+            ];
+            if !outgoing.is_empty() {
+                fds[1].events |= libc::POLLOUT;
+            }
+            if !incoming.is_empty() {
+                fds[0].events |= libc::POLLOUT;
+            }
+            unsafe {
+                libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, 50);
+            }
+        }
+    }
+    // a non-blocking session returns `EAGAIN`, retry until it is done
+    while let Err(e) = channel.close() {
+        if e.code() != ErrorCode::Session(LIBSSH2_ERROR_EAGAIN) {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    Ok(())
+}
+
+fn is_retryable(e: &std::io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::Interrupted)
+}
+
+/// serialize updates of known hosts files
+static KNOWN_HOSTS_LOCK: Mutex<()> = Mutex::new(());
+
+/// check the host key against `host_key` or the known hosts file
+fn verify_host_key(session: &Session, host: &Host) -> Result<()> {
+    let (key, key_type) = session
+        .host_key()
+        .ok_or_else(|| anyhow!("missing host key of \"{}\"", host.hostname))?;
+    let fingerprint = session
+        .host_key_hash(HashType::Sha256)
+        .map(|h| format!("SHA256:{}", encode_block(h).trim_end_matches('=')))
+        .unwrap_or_default();
+    if let Some(host_key) = &host.host_key {
+        // `<type> <base64 key> [comment]` or `<base64 key>`
+        let mut fields = host_key.split_whitespace();
+        let encoded = match (fields.next(), fields.next()) {
+            (Some(_), Some(encoded)) | (Some(encoded), None) => encoded,
+            _ => bail!("invalid host_key of \"{}\"", host.hostname),
+        };
+        if decode_block(encoded)? != key {
+            bail!(
+                "host key of \"{}\" does not match the configured host_key, got {}",
+                host.hostname,
+                fingerprint
+            );
+        }
+        return Ok(());
+    }
+
+    let file = match &host.known_hosts_file {
+        Some(file) => PathBuf::from(file),
+        None => {
+            let home = std::env::var("HOME")
+                .map_err(|_| anyhow!("\"HOME\" environment variable is required"))?;
+            Path::new(&home).join(".ssh/known_hosts")
+        }
+    };
+    let port = host.port.unwrap_or(22);
+    let _lock = KNOWN_HOSTS_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut known_hosts = session.known_hosts()?;
+    if file.exists() {
+        known_hosts.read_file(&file, KnownHostFileKind::OpenSSH)?;
+    }
+    match known_hosts.check_port(&host.hostname, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::Mismatch => bail!(
+            "host key of \"{}\" does not match {}, got {}, \
+            the host may be reinstalled or someone is intercepting the connection",
+            host.hostname,
+            file.to_string_lossy(),
+            fingerprint
+        ),
+        CheckResult::NotFound if host.host_key_check == HostKeyCheck::AcceptNew => {
+            let name = if port == 22 {
+                host.hostname.clone()
+            } else {
+                format!("[{}]:{}", host.hostname, port)
+            };
+            let key_type = match key_type {
+                HostKeyType::Rsa => "ssh-rsa",
+                HostKeyType::Dss => "ssh-dss",
+                HostKeyType::Ecdsa256 => "ecdsa-sha2-nistp256",
+                HostKeyType::Ecdsa384 => "ecdsa-sha2-nistp384",
+                HostKeyType::Ecdsa521 => "ecdsa-sha2-nistp521",
+                HostKeyType::Ed25519 => "ssh-ed25519",
+                HostKeyType::Unknown => bail!("unknown host key type of \"{}\"", host.hostname),
+            };
+            // append instead of rewriting, lines not understood by libssh2 are kept
+            if let Some(dir) = file.parent() {
+                create_dir_all(dir)?;
+            }
+            let mut known_hosts_file = OpenOptions::new().create(true).append(true).open(&file)?;
+            let ends_with_newline = std::fs::read(&file)?.last().is_none_or(|b| *b == b'\n');
+            writeln!(
+                known_hosts_file,
+                "{}{} {} {}",
+                if ends_with_newline { "" } else { "\n" },
+                name,
+                key_type,
+                encode_block(key)
+            )?;
+            warn!(
+                "host key {} of \"{}\" is added to {}",
+                fingerprint,
+                host.hostname,
+                file.to_string_lossy()
+            );
+            Ok(())
+        }
+        CheckResult::NotFound => bail!(
+            "host key {} of \"{}\" is not found in {}",
+            fingerprint,
+            host.hostname,
+            file.to_string_lossy()
+        ),
+        CheckResult::Failure => bail!("failed to check host key of \"{}\"", host.hostname),
+    }
+}
+
+/// try `use_agent`, `identity_file` and `password` in order, the default keys are tried
+/// if none of them is configured
+fn authenticate(session: &Session, host: &Host) -> Result<()> {
+    let home = || {
+        std::env::var("HOME")
+            .map(PathBuf::from)
+            .map_err(|_| anyhow!("\"HOME\" environment variable is required"))
+    };
+    let mut errors = Vec::new();
+    if host.use_agent {
+        match session.userauth_agent(&host.user) {
+            Ok(()) => return Ok(()),
+            Err(e) => errors.push(format!("agent: {}", e)),
+        }
+    }
+    let identity_file = match &host.identity_file {
+        Some(file) => match file.strip_prefix("~/") {
+            Some(file) => Some(home()?.join(file)),
+            None => Some(PathBuf::from(file)),
+        },
+        None if !host.use_agent && host.password.is_none() => {
+            let ssh_dir = home()?.join(".ssh");
+            let key = ["id_ed25519", "id_rsa"]
+                .iter()
+                .map(|k| ssh_dir.join(k))
+                .find(|k| k.exists());
+            Some(key.ok_or_else(|| anyhow!("missing private key"))?)
+        }
+        None => None,
+    };
+    if let Some(file) = identity_file {
+        let passphrase = host.passphrase.as_ref().map(Secret::read).transpose()?;
+        match session.userauth_pubkey_file(&host.user, None, &file, passphrase.as_deref()) {
+            Ok(()) => return Ok(()),
+            Err(e) => errors.push(format!("{}: {}", file.to_string_lossy(), e)),
+        }
+    }
+    if let Some(password) = &host.password {
+        match session.userauth_password(&host.user, &password.read()?) {
+            Ok(()) => return Ok(()),
+            Err(e) => errors.push(format!("password: {}", e)),
+        }
+    }
+    bail!(
+        "failed to authenticate \"{}\" on \"{}\": {}",
+        host.user,
+        host.hostname,
+        errors.join(", ")
+    )
+}
+
+/// why reading the output is stopped before the end
+enum Interrupt {
+    TimedOut,
+    /// the run is cancelled, see `SessionPool::cancel_on_drop`
+    Cancelled,
+}
+
+/// read stdout and stderr of `channel` to the end from a non-blocking session,
+/// stop if `deadline` is reached or the run of `pool` is cancelled
+fn read_output(
+    session: &Session,
+    channel: &mut Channel,
+    output: &mut Output,
+    deadline: Option<Instant>,
+    pool: &SessionPool,
+) -> Result<Option<Interrupt>> {
+    let mut chunk = [0; 8192];
+    let mut eof = [false, false];
+    while !(eof[0] && eof[1]) {
+        if pool.is_cancelled() {
+            return Ok(Some(Interrupt::Cancelled));
+        }
+        // checked on every read, a command printing endlessly is timed out too
+        let remaining = deadline.map(|d| d.saturating_duration_since(Instant::now()));
+        if remaining.is_some_and(|r| r.is_zero()) {
+            return Ok(Some(Interrupt::TimedOut));
+        }
+        let mut progressed = false;
+        for (index, &stream) in [Stream::Stdout, Stream::Stderr].iter().enumerate() {
+            if eof[index] {
+                continue;
+            }
+            match channel.stream(index as i32).read(&mut chunk) {
+                Ok(0) if channel.eof() => eof[index] = true,
+                Ok(0) => {}
+                Ok(n) => {
+                    output.push(stream, &chunk[..n]);
+                    progressed = true;
+                }
+                Err(e) if is_retryable(&e) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if progressed {
+            continue;
+        }
+        let wait = remaining.map_or(100, |r| r.as_millis().clamp(1, 100) as i32);
+        let mut fd = libc::pollfd {
+            fd: session.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe {
+            libc::poll(&mut fd, 1, wait);
+        }
     }
+    Ok(None)
 }
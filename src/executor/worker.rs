@@ -0,0 +1,88 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use http::{header::CONTENT_TYPE, Request, Response};
+use hyper::{Body, Error};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use super::queue;
+use crate::constants::CONFIG;
+
+/// runs are started in FIFO order once a permit is available
+static WORKERS: Lazy<Semaphore> = Lazy::new(|| {
+    let max = CONFIG
+        .worker
+        .as_ref()
+        .and_then(|w| w.max_concurrency)
+        .unwrap_or(Semaphore::MAX_PERMITS);
+    Semaphore::new(max)
+});
+static RUNNING: AtomicUsize = AtomicUsize::new(0);
+static WAITING: AtomicUsize = AtomicUsize::new(0);
+
+/// a running run, the worker is released on drop
+pub struct Worker {
+    _permit: SemaphorePermit<'static>,
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        RUNNING.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct Waiting;
+
+impl Drop for Waiting {
+    fn drop(&mut self) {
+        WAITING.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// wait for a worker
+pub async fn acquire() -> Worker {
+    WAITING.fetch_add(1, Ordering::Relaxed);
+    let waiting = Waiting;
+    // the semaphore is never closed
+    let permit = WORKERS.acquire().await.unwrap();
+    drop(waiting);
+    RUNNING.fetch_add(1, Ordering::Relaxed);
+    Worker { _permit: permit }
+}
+
+/// number of runs which are not started yet
+pub fn backlog() -> usize {
+    WAITING.load(Ordering::Relaxed) + queue::pending()
+}
+
+/// new runs are rejected if the backlog exceeds `worker.max_backlog`
+pub fn is_busy() -> bool {
+    match CONFIG.worker.as_ref().and_then(|w| w.max_backlog) {
+        Some(max) => backlog() >= max,
+        None => false,
+    }
+}
+
+#[derive(Serialize)]
+struct Status {
+    running: usize,
+    /// waiting for a worker
+    waiting: usize,
+    /// waiting for the previous run of the same repository
+    pending: usize,
+}
+
+pub async fn status_handler(_req: Request<Body>) -> Result<Response<Body>, Error> {
+    let status = Status {
+        running: RUNNING.load(Ordering::Relaxed),
+        waiting: WAITING.load(Ordering::Relaxed),
+        pending: queue::pending(),
+    };
+    // serializing a struct of numbers never fails
+    let body = serde_json::to_string(&status).unwrap();
+    let mut resp = Response::new(Body::from(body));
+    resp.headers_mut()
+        .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    Ok(resp)
+}
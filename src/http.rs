@@ -11,8 +11,12 @@ use hyper_tls::HttpsConnector;
 use routerify::{Router, RouterBuilder, RouterService};
 
 use crate::{
-    executor::logs_handler,
-    trigger::{gitea_trigger, coding_trigger, manual_trigger},
+    constants::CONFIG,
+    executor::{logs_handler, status_handler},
+    trigger::{
+        coding_trigger, generic_trigger, gitea_trigger, github_trigger, gitlab_trigger,
+        manual_trigger,
+    },
 };
 
 pub struct Server {
@@ -76,9 +80,21 @@ pub fn new_server<T: ToSocketAddrs>(addr: T) -> Result<Server> {
     let server = Server::new(addr)?;
     let server = server
         .post("/gitea_trigger", gitea_trigger)
+        .post("/github_trigger", github_trigger)
+        .post("/gitlab_trigger", gitlab_trigger)
         .post("/coding_trigger", coding_trigger)
         .post("/manual_trigger", manual_trigger)
-        .get("/logs", logs_handler);
+        .get("/logs", logs_handler)
+        .get("/status", status_handler);
+    let generic = CONFIG.trigger.as_ref().and_then(|t| t.generic.as_ref());
+    let server = generic
+        .into_iter()
+        .flatten()
+        .fold(server, |server, endpoint| {
+            server.post(endpoint.path.as_str(), move |req| {
+                generic_trigger(endpoint, req)
+            })
+        });
     Ok(server)
 }
 
@@ -1,8 +1,11 @@
+mod condition;
 mod config;
 pub mod constants;
 pub mod executor;
 pub mod http;
 pub mod notifier;
+mod pattern;
+pub mod scheduler;
 pub mod trigger;
 
 fn tmp_filename(len: usize) -> String {
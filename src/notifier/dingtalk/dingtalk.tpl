@@ -11,7 +11,9 @@
 **logs:**
 
 {% for log in logs -%}
-{{loop.index}}. {{log.description}} {% if log.stdout -%}
+{{loop.index}}. {{log.description}} {% if log.timed_out -%}
+        (timed out)
+    {%- endif %} {% if log.stdout -%}
         [stdout]({{log.stdout}})
     {%- else -%}
         stdout
@@ -19,5 +21,28 @@
         [stderr]({{log.stderr}})
     {%- else -%}
         stderr
-    {%- endif %}
+    {%- endif %}{% if log.output %}, [output]({{log.output}}){% endif %}{% if log.error %} (error: {{log.error}}){% endif %}{% if log.attempts > 1 %} (attempts: {{log.attempts}}
+    {%- for attempt in log.previous_attempts -%}
+        , {{loop.index}}: {% if attempt.stdout -%}
+            [stdout]({{attempt.stdout}})
+        {%- else -%}
+            stdout
+        {%- endif %} {% if attempt.stderr -%}
+            [stderr]({{attempt.stderr}})
+        {%- else -%}
+            stderr
+        {%- endif %}
+    {%- endfor %}){% endif %}
+{% for host in log.hosts %}    - {{host.host}} {% if host.timed_out -%}
+        (timed out)
+    {%- endif %} {% if host.stdout -%}
+        [stdout]({{host.stdout}})
+    {%- else -%}
+        stdout
+    {%- endif %}, {% if host.stderr -%}
+        [stderr]({{host.stderr}})
+    {%- else -%}
+        stderr
+    {%- endif %}{% if host.output %}, [output]({{host.output}}){% endif %}{% if host.error %} (error: {{host.error}}){% endif %}{% if host.attempts > 1 %} (attempts: {{host.attempts}}){% endif %}
 {% endfor %}
+{%- endfor %}
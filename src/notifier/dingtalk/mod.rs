@@ -88,6 +88,7 @@ impl<'a> DingTalk<'a> {
     }
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, Deserialize)]
 pub struct Respond {
     errcode: i32,
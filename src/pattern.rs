@@ -0,0 +1,150 @@
+use std::convert::TryFrom;
+
+use anyhow::{anyhow, Error, Result};
+use globset::{GlobBuilder, GlobMatcher};
+use regex::Regex;
+use serde::Deserialize;
+
+/// one or more patterns, a value is matched if it is matched by any of the
+/// inclusive patterns (or there is none) and none of the exclusive patterns
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "FilterForm")]
+pub enum Filter {
+    Single(Pattern),
+    Multiple(Vec<Pattern>),
+}
+
+/// patterns are parsed after the form is matched, an untagged enum of patterns
+/// would hide the error of an invalid pattern behind "did not match any variant"
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FilterForm {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl TryFrom<FilterForm> for Filter {
+    type Error = Error;
+
+    fn try_from(form: FilterForm) -> Result<Filter> {
+        Ok(match form {
+            FilterForm::Single(s) => Filter::Single(Pattern::try_from(s)?),
+            FilterForm::Multiple(m) => Filter::Multiple(
+                m.into_iter()
+                    .map(Pattern::try_from)
+                    .collect::<Result<_>>()?,
+            ),
+        })
+    }
+}
+
+impl Filter {
+    pub fn patterns(&self) -> &[Pattern] {
+        match self {
+            Filter::Single(s) => std::slice::from_ref(s),
+            Filter::Multiple(m) => m,
+        }
+    }
+
+    pub fn is_match(&self, value: Option<&str>) -> bool {
+        let value = match value {
+            Some(v) => v,
+            None => return false,
+        };
+        let patterns = self.patterns();
+        let mut include = patterns.iter().filter(|p| !p.exclude).peekable();
+        let included = include.peek().is_none() || include.any(|p| p.is_match(value));
+        included && !patterns.iter().any(|p| p.exclude && p.is_match(value))
+    }
+}
+
+/// a pattern is one of:
+///
+/// * `@any`, match everything
+/// * `re:<regex>`, e.g. `re:^v\d+\.\d+\.\d+$`
+/// * a glob, e.g. `release/*`, `v[0-9]*`, a plain name is matched exactly
+///
+/// a pattern prefixed with `!` is an exclusive pattern, e.g. `!wip/*`
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Pattern {
+    exclude: bool,
+    matcher: Matcher,
+}
+
+#[derive(Debug, Clone)]
+enum Matcher {
+    Any,
+    Glob(GlobMatcher),
+    Regex(Regex),
+}
+
+impl Pattern {
+    pub fn is_match(&self, value: &str) -> bool {
+        match &self.matcher {
+            Matcher::Any => true,
+            Matcher::Glob(g) => g.is_match(value),
+            Matcher::Regex(r) => r.is_match(value),
+        }
+    }
+}
+
+impl TryFrom<String> for Pattern {
+    type Error = Error;
+
+    fn try_from(source: String) -> Result<Pattern> {
+        let (exclude, pattern) = match source.strip_prefix('!') {
+            Some(p) => (true, p),
+            None => (false, source.as_str()),
+        };
+        let matcher =
+            matcher(pattern).map_err(|e| anyhow!("invalid pattern \"{}\": {}", source, e))?;
+        Ok(Pattern { exclude, matcher })
+    }
+}
+
+fn matcher(pattern: &str) -> Result<Matcher> {
+    Ok(if pattern == "@any" {
+        Matcher::Any
+    } else if let Some(re) = pattern.strip_prefix("re:") {
+        Matcher::Regex(Regex::new(re)?)
+    } else {
+        let glob = GlobBuilder::new(pattern).literal_separator(true).build()?;
+        Matcher::Glob(glob.compile_matcher())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(source: &str) -> Result<Filter, serde_yaml::Error> {
+        serde_yaml::from_str(source)
+    }
+
+    #[test]
+    fn invalid_pattern_error() {
+        let e = filter("re:[").unwrap_err().to_string();
+        assert!(e.contains("invalid pattern \"re:[\""), "{}", e);
+        let e = filter("[main, \"!re:(\"]").unwrap_err().to_string();
+        assert!(e.contains("invalid pattern \"!re:(\""), "{}", e);
+    }
+
+    #[test]
+    fn exclusion() {
+        let f = filter("[\"release/*\", \"!release/wip-*\"]").unwrap();
+        assert!(f.is_match(Some("release/1.0")));
+        assert!(!f.is_match(Some("release/wip-1.0")));
+        assert!(!f.is_match(Some("main")));
+        assert!(!f.is_match(None));
+
+        // only exclusive patterns match everything else
+        let f = filter("\"!re:^wip\"").unwrap();
+        assert!(f.is_match(Some("main")));
+        assert!(!f.is_match(Some("wip-1")));
+
+        let f = filter("[\"@any\", \"!main\"]").unwrap();
+        assert!(f.is_match(Some("dev")));
+        assert!(!f.is_match(Some("main")));
+    }
+}
@@ -0,0 +1,215 @@
+use std::convert::TryFrom;
+
+use anyhow::{anyhow, bail, Error, Result};
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate, NaiveDateTime, TimeZone, Timelike};
+use serde::Deserialize;
+
+/// a five fields cron expression: `minute hour day-of-month month day-of-week`,
+/// each field is `*`, a number, a range `a-b`, a step `*/n` or `a-b/n`, or a list of them,
+/// `@yearly`, `@monthly`, `@weekly`, `@daily` and `@hourly` are accepted too
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Cron {
+    expression: String,
+    minute: u64,
+    hour: u64,
+    day: u64,
+    month: u64,
+    weekday: u64,
+    /// day-of-month is `*`
+    any_day: bool,
+    /// day-of-week is `*`
+    any_weekday: bool,
+}
+
+impl Cron {
+    pub fn expression(&self) -> &str {
+        &self.expression
+    }
+
+    /// the first tick later than `time`
+    pub fn next_after(&self, time: DateTime<Local>) -> Option<DateTime<Local>> {
+        let mut time = time.naive_local();
+        loop {
+            let next = self.next_naive_after(time)?;
+            // skip the ticks in the gap of daylight saving time
+            match Local.from_local_datetime(&next).earliest() {
+                Some(next) => return Some(next),
+                None => time = next,
+            }
+        }
+    }
+
+    fn next_naive_after(&self, time: NaiveDateTime) -> Option<NaiveDateTime> {
+        let mut t = time.date().and_hms_opt(time.hour(), time.minute(), 0)? + Duration::minutes(1);
+        let limit = t + Duration::days(366 * 5);
+        while t < limit {
+            if !is_set(self.month, t.month()) {
+                let (year, month) = if t.month() == 12 {
+                    (t.year() + 1, 1)
+                } else {
+                    (t.year(), t.month() + 1)
+                };
+                t = NaiveDate::from_ymd_opt(year, month, 1)?.and_hms_opt(0, 0, 0)?;
+            } else if !self.match_day(t.date()) {
+                t = (t.date() + Duration::days(1)).and_hms_opt(0, 0, 0)?;
+            } else if !is_set(self.hour, t.hour()) {
+                t = t.date().and_hms_opt(t.hour(), 0, 0)? + Duration::hours(1);
+            } else if !is_set(self.minute, t.minute()) {
+                t += Duration::minutes(1);
+            } else {
+                return Some(t);
+            }
+        }
+        None
+    }
+
+    /// like the vixie cron, a day is matched by either field if both fields are restricted
+    fn match_day(&self, date: NaiveDate) -> bool {
+        let day = is_set(self.day, date.day());
+        let weekday = is_set(self.weekday, date.weekday().num_days_from_sunday());
+        match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (true, false) => weekday,
+            (false, true) => day,
+            (false, false) => day || weekday,
+        }
+    }
+}
+
+fn is_set(bits: u64, n: u32) -> bool {
+    bits & (1 << n) != 0
+}
+
+/// parse a field into a bit set, bit `n` is set if `n` is matched
+fn parse_field(field: &str, min: u32, max: u32) -> Option<u64> {
+    let mut bits = 0;
+    for item in field.split(',') {
+        let (range, step) = match item.split_once('/') {
+            Some((range, step)) => (range, step.parse().ok()?),
+            None => (item, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some((start, end)) = range.split_once('-') {
+            (start.parse().ok()?, end.parse().ok()?)
+        } else {
+            let start = range.parse().ok()?;
+            // `a/n` means from `a` to the max
+            (start, if item.contains('/') { max } else { start })
+        };
+        if step == 0 || start < min || end > max || start > end {
+            return None;
+        }
+        for n in (start..=end).step_by(step) {
+            bits |= 1 << n;
+        }
+    }
+    Some(bits)
+}
+
+impl TryFrom<String> for Cron {
+    type Error = Error;
+
+    fn try_from(source: String) -> Result<Cron> {
+        let expression = match source.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            e => e,
+        };
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        if fields.len() != 5 {
+            bail!("invalid cron expression \"{}\"", expression);
+        }
+        let field = |index: usize, min, max| {
+            parse_field(fields[index], min, max)
+                .ok_or_else(|| anyhow!("invalid cron field \"{}\"", fields[index]))
+        };
+        let mut weekday = field(4, 0, 7)?;
+        // both 0 and 7 are sunday
+        if is_set(weekday, 7) {
+            weekday |= 1;
+        }
+        let cron = Cron {
+            expression: source.clone(),
+            minute: field(0, 0, 59)?,
+            hour: field(1, 0, 23)?,
+            day: field(2, 1, 31)?,
+            month: field(3, 1, 12)?,
+            weekday,
+            any_day: fields[2] == "*",
+            any_weekday: fields[4] == "*",
+        };
+        Ok(cron)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn next(cron: &str, time: &str) -> Option<String> {
+        let cron = Cron::try_from(cron.to_string()).unwrap();
+        let time = NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M").unwrap();
+        let time = Local.from_local_datetime(&time).unwrap();
+        cron.next_after(time)
+            .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+    }
+
+    #[test]
+    fn weekday_range() {
+        // 2022-01-07 is a friday
+        let next = next("0 9 * * 1-5", "2022-01-07 10:00");
+        assert_eq!(next.as_deref(), Some("2022-01-10 09:00"));
+    }
+
+    #[test]
+    fn step() {
+        let next_15 = next("*/15 * * * *", "2022-01-07 10:07");
+        assert_eq!(next_15.as_deref(), Some("2022-01-07 10:15"));
+        let next_6h = next("0 */6 * * *", "2022-01-07 18:00");
+        assert_eq!(next_6h.as_deref(), Some("2022-01-08 00:00"));
+        let next_ranged = next("0 1-10/4 * * *", "2022-01-07 05:30");
+        assert_eq!(next_ranged.as_deref(), Some("2022-01-07 09:00"));
+    }
+
+    #[test]
+    fn sunday() {
+        // 2022-01-09 is a sunday
+        let next_7 = next("30 8 * * 7", "2022-01-07 10:00");
+        let next_0 = next("30 8 * * 0", "2022-01-07 10:00");
+        assert_eq!(next_7.as_deref(), Some("2022-01-09 08:30"));
+        assert_eq!(next_7, next_0);
+        let next_range = next("0 0 * * 5-7", "2022-01-09 10:00");
+        assert_eq!(next_range.as_deref(), Some("2022-01-14 00:00"));
+    }
+
+    #[test]
+    fn day_or_weekday() {
+        // the 13th or a friday
+        let next = next("0 0 13 * 5", "2022-01-07 10:00");
+        assert_eq!(next.as_deref(), Some("2022-01-13 00:00"));
+    }
+
+    #[test]
+    fn impossible_date() {
+        assert_eq!(next("0 0 30 2 *", "2022-01-07 10:00"), None);
+        assert_eq!(next("0 0 31 4,6,9,11 *", "2022-01-07 10:00"), None);
+    }
+
+    #[test]
+    fn invalid() {
+        for cron in &[
+            "* * * *",
+            "60 * * * *",
+            "* * 0 * *",
+            "*/0 * * * *",
+            "5-1 * * * *",
+        ] {
+            assert!(Cron::try_from(cron.to_string()).is_err(), "{}", cron);
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, TimeZone};
+use log::{error, info};
+use openssl::hash::{hash, MessageDigest};
+use tokio::fs::{create_dir_all, read_to_string, write};
+
+use crate::{
+    config::{Config, Missed, Repository, Schedule},
+    trigger::{Metadata, TriggeredInfo},
+};
+
+mod cron;
+
+pub use cron::Cron;
+
+/// run scheduled repositories in background
+pub fn start(config: &'static Config) {
+    for (index, repo) in config.repository.iter().enumerate() {
+        if let Some(schedule) = &repo.schedule {
+            tokio::spawn(async move {
+                if let Err(e) = run(config, index, repo, schedule).await {
+                    error!("schedule of {} error: {}", repo.name, e);
+                }
+            });
+        }
+    }
+}
+
+async fn run(
+    config: &Config,
+    index: usize,
+    repo: &'static Repository,
+    schedule: &Schedule,
+) -> Result<()> {
+    let state = state_file(config, repo, schedule)?;
+    let now = Local::now();
+    if let Some(last) = last_tick(&state).await {
+        if let Some(next) = schedule.cron.next_after(last) {
+            if next <= now {
+                match schedule.missed {
+                    Missed::Skip => info!("skip missed schedule of {} at {}", repo.name, next),
+                    Missed::RunOnce => tick(index, repo, schedule, &state, now).await,
+                }
+            }
+        }
+    }
+    let mut last = now;
+    loop {
+        let next = schedule
+            .cron
+            .next_after(last)
+            .ok_or_else(|| anyhow!("no more tick"))?;
+        let wait = (next - Local::now()).to_std().unwrap_or_default();
+        tokio::time::sleep(wait).await;
+        tick(index, repo, schedule, &state, next).await;
+        last = next;
+    }
+}
+
+async fn tick(
+    index: usize,
+    repo: &'static Repository,
+    schedule: &Schedule,
+    state: &Path,
+    time: DateTime<Local>,
+) {
+    let steps_name = schedule.steps_name.clone().or_else(|| {
+        repo.match_steps_name(schedule.branch.as_deref(), schedule.tag.as_deref())
+            .map(ToString::to_string)
+    });
+    let info = TriggeredInfo {
+        repository: repo.name.clone(),
+        branch: schedule.branch.clone(),
+        tag: schedule.tag.clone(),
+        steps_name,
+        environment: None,
+        event: Some("schedule".to_string()),
+        changed_files: None,
+        metadata: Metadata::default(),
+    };
+    info.spawn(index, repo);
+    // a missed tick may run again after restart, but the schedule goes on
+    if let Err(e) = write(state, time.timestamp().to_string()).await {
+        error!("save schedule state of {} error: {}", repo.name, e);
+    }
+}
+
+/// the time of last tick is saved in this file, it is used to find missed ticks
+fn state_file(config: &Config, repo: &Repository, schedule: &Schedule) -> Result<PathBuf> {
+    let key = format!(
+        "{}\n{}\n{}\n{}\n{}",
+        repo.name,
+        schedule.cron.expression(),
+        schedule.branch.as_deref().unwrap_or(""),
+        schedule.tag.as_deref().unwrap_or(""),
+        schedule.steps_name.as_deref().unwrap_or("")
+    );
+    let key = hex::encode(hash(MessageDigest::sha1(), key.as_bytes())?);
+    let dir = config.work_dir.as_deref().unwrap_or("/tmp");
+    Ok(Path::new(dir).join("cache").join("schedule").join(key))
+}
+
+async fn last_tick(state: &Path) -> Option<DateTime<Local>> {
+    if let Some(dir) = state.parent() {
+        if let Err(e) = create_dir_all(dir).await {
+            error!("create schedule directory error: {}", e);
+        }
+    }
+    let timestamp = read_to_string(state).await.ok()?.trim().parse().ok()?;
+    Local.timestamp_opt(timestamp, 0).single()
+}
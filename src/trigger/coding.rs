@@ -1,8 +1,15 @@
+use std::convert::TryInto;
 
 use anyhow::{anyhow, bail, Result};
 use hyper::{header::CONTENT_TYPE, Body, Error, Request, Response};
+use openssl::hash::MessageDigest;
 use serde::Deserialize;
 
+use super::{
+    changed_files, parse_ref, secure_eq, signature, ChangedFiles, Metadata, TriggeredInfo,
+};
+use crate::constants::CONFIG;
+
 /// coding.net code push event of service hook
 pub async fn trigger(req: Request<Body>) -> Result<Response<Body>, Error> {
     match inner_trigger(req).await {
@@ -13,22 +20,101 @@ pub async fn trigger(req: Request<Body>) -> Result<Response<Body>, Error> {
 
 async fn inner_trigger(req: Request<Body>) -> Result<Response<Body>> {
     let (parts, body) = req.into_parts();
-    //dbg!(&parts, &body);
-    let x = hyper::body::to_bytes(body).await?;
-    dbg!(&parts.headers);
-    dbg!(&x);
-    // "x-coding-signature"
-    // TODO: parse coding web hook request body
-    Ok(Response::new(Body::empty()))
+    match parts.headers.get(CONTENT_TYPE) {
+        Some(c) => {
+            if c != "application/json" {
+                bail!("invalid content-type");
+            }
+        }
+        None => bail!("missing content-type"),
+    }
+    let event = parts
+        .headers
+        .get("X-Coding-Event")
+        .ok_or_else(|| anyhow!("missing event"))?;
+    if event != "push" {
+        bail!("unsupported event");
+    }
+    let header_signature = parts
+        .headers
+        .get("X-Coding-Signature")
+        .ok_or_else(|| anyhow!("missing signature"))?;
+    let body = hyper::body::to_bytes(body).await?;
+    let trigger_secret = match CONFIG.trigger.as_ref().and_then(|t| t.coding.as_ref()) {
+        Some(coding) => &coding.secret,
+        None => bail!("missing trigger.coding.secret in config"),
+    };
+    let payload_signature = format!(
+        "sha1={}",
+        signature(MessageDigest::sha1(), trigger_secret, &body)?
+    );
+    if !secure_eq(header_signature.as_bytes(), payload_signature.as_bytes()) {
+        bail!("signature error");
+    }
+    let body: CodingForm = serde_json::from_slice(&body)?;
+    let info: TriggeredInfo = body.try_into()?;
+    let result = info.delivery(&CONFIG).await?;
+    Ok(result.into())
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CodingForm {
+    #[serde(rename = "ref")]
+    _ref: String,
+    before: Option<String>,
+    after: Option<String>,
+    repository: Repository,
+    #[serde(default)]
+    commits: Vec<ChangedFiles>,
+    head_commit: Option<Commit>,
+    sender: Option<User>,
+}
+
+impl TryInto<TriggeredInfo> for CodingForm {
+    type Error = anyhow::Error;
+
+    fn try_into(self) -> Result<TriggeredInfo> {
+        let (branch, tag) = parse_ref(&self._ref)?;
+        let info = TriggeredInfo {
+            repository: self.repository.full_name,
+            branch,
+            tag,
+            steps_name: None,
+            environment: None,
+            event: Some("push".to_string()),
+            changed_files: changed_files(&self.commits),
+            metadata: Metadata {
+                message: self.head_commit.as_ref().map(|c| c.message.clone()),
+                author: self.head_commit.map(|c| c.author.name),
+                before: self.before,
+                after: self.after,
+                pusher: self.sender.map(|s| s.login),
+                clone_url: self.repository.clone_url,
+                ..Default::default()
+            },
+        };
+        Ok(info)
+    }
 }
 
-fn signature(key: &str, payload: &[u8]) -> Result<String> {
-    use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
+#[derive(Debug, Clone, Deserialize)]
+pub struct Repository {
+    pub full_name: String,
+    pub clone_url: Option<String>,
+}
 
-    let key = PKey::hmac(key.as_bytes())?;
-    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
-    signer.update(payload)?;
-    let hmac = signer.sign_to_vec()?;
-    Ok(hex::encode(hmac))
+#[derive(Debug, Clone, Deserialize)]
+pub struct Commit {
+    message: String,
+    author: Author,
 }
 
+#[derive(Debug, Clone, Deserialize)]
+pub struct Author {
+    name: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct User {
+    login: String,
+}
@@ -0,0 +1,80 @@
+/// deployment directive in commit message, e.g. `[skip deploy]`, `[deploy:prod]`
+#[derive(Debug, Clone, PartialEq)]
+pub enum Directive<'a> {
+    /// `[skip deploy]`, `[deploy skip]`, `[ci skip]` or `[skip ci]`
+    Skip,
+    /// `[deploy:<steps name>]`
+    Steps(&'a str),
+}
+
+impl<'a> Directive<'a> {
+    /// `Skip` takes precedence over `Steps` if both are present
+    pub fn parse(message: &'a str) -> Option<Directive<'a>> {
+        let mut directive = None;
+        let mut rest = message;
+        while let Some(start) = rest.find('[') {
+            rest = &rest[start + 1..];
+            let end = match rest.find(']') {
+                Some(end) => end,
+                None => break,
+            };
+            let token = rest[..end].trim();
+            rest = &rest[end + 1..];
+            match token.to_ascii_lowercase().as_str() {
+                "skip deploy" | "deploy skip" | "ci skip" | "skip ci" => {
+                    return Some(Directive::Skip)
+                }
+                t if t.starts_with("deploy:") => {
+                    let steps_name = token["deploy:".len()..].trim();
+                    if directive.is_none() && !steps_name.is_empty() {
+                        directive = Some(Directive::Steps(steps_name));
+                    }
+                }
+                _ => {}
+            }
+        }
+        directive
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn skip() {
+        assert_eq!(
+            Directive::parse("fix typo [CI Skip]"),
+            Some(Directive::Skip)
+        );
+        assert_eq!(
+            Directive::parse("[ skip deploy ] wip"),
+            Some(Directive::Skip)
+        );
+        // skip takes precedence over an earlier steps name
+        let message = "[deploy:prod] fix typo [skip ci]";
+        assert_eq!(Directive::parse(message), Some(Directive::Skip));
+    }
+
+    #[test]
+    fn steps() {
+        let message = "release [deploy: prod ]";
+        assert_eq!(Directive::parse(message), Some(Directive::Steps("prod")));
+        let message = "[Deploy:staging] then [deploy:prod]";
+        assert_eq!(Directive::parse(message), Some(Directive::Steps("staging")));
+        assert_eq!(Directive::parse("[deploy:]"), None);
+        let message = "[deploy:] [deploy:prod]";
+        assert_eq!(Directive::parse(message), Some(Directive::Steps("prod")));
+    }
+
+    #[test]
+    fn no_directive() {
+        assert_eq!(Directive::parse("fix [skip"), None);
+        assert_eq!(Directive::parse("fix [deploy:prod"), None);
+        assert_eq!(
+            Directive::parse("[deploy:prod] [skip ci"),
+            Some(Directive::Steps("prod"))
+        );
+        assert_eq!(Directive::parse("[wip] fix"), None);
+    }
+}
@@ -0,0 +1,107 @@
+use anyhow::{anyhow, bail, Result};
+use hyper::{header::CONTENT_TYPE, Body, Error, Request, Response};
+use openssl::hash::MessageDigest;
+use serde_json::Value;
+
+use super::{secure_eq, signature, Metadata, TriggeredInfo};
+use crate::{
+    config::{GenericAuth, GenericTrigger, HmacAlgorithm},
+    constants::CONFIG,
+};
+
+/// generic webhook, fields are extracted from json body as configured in `endpoint`
+pub async fn trigger(
+    endpoint: &'static GenericTrigger,
+    req: Request<Body>,
+) -> Result<Response<Body>, Error> {
+    match inner_trigger(endpoint, req).await {
+        Ok(r) => Ok(r),
+        Err(e) => Ok(Response::new(Body::from(e.to_string()))),
+    }
+}
+
+async fn inner_trigger(
+    endpoint: &'static GenericTrigger,
+    req: Request<Body>,
+) -> Result<Response<Body>> {
+    let (parts, body) = req.into_parts();
+    match parts.headers.get(CONTENT_TYPE) {
+        Some(c) => {
+            if !c.as_bytes().starts_with(b"application/json") {
+                bail!("invalid content-type");
+            }
+        }
+        None => bail!("missing content-type"),
+    }
+    let body = hyper::body::to_bytes(body).await?;
+    match &endpoint.auth {
+        GenericAuth::Token { header, token } => {
+            let header_token = parts
+                .headers
+                .get(header.as_str())
+                .ok_or_else(|| anyhow!("missing token"))?;
+            if !secure_eq(header_token.as_bytes(), token.as_bytes()) {
+                bail!("token error");
+            }
+        }
+        GenericAuth::Hmac {
+            header,
+            algorithm,
+            secret,
+            prefix,
+        } => {
+            let header_signature = parts
+                .headers
+                .get(header.as_str())
+                .ok_or_else(|| anyhow!("missing signature"))?;
+            let digest = match algorithm {
+                HmacAlgorithm::Sha1 => MessageDigest::sha1(),
+                HmacAlgorithm::Sha256 => MessageDigest::sha256(),
+                HmacAlgorithm::Sha512 => MessageDigest::sha512(),
+            };
+            let payload_signature = format!(
+                "{}{}",
+                prefix.as_deref().unwrap_or(""),
+                signature(digest, secret, &body)?
+            );
+            if !secure_eq(header_signature.as_bytes(), payload_signature.as_bytes()) {
+                bail!("signature error");
+            }
+        }
+    }
+    let body: Value = serde_json::from_slice(&body)?;
+    let info = extract(endpoint, &body)?;
+    let result = info.delivery(&CONFIG).await?;
+    Ok(result.into())
+}
+
+fn extract(endpoint: &GenericTrigger, body: &Value) -> Result<TriggeredInfo> {
+    let get = |pointer: &Option<String>| pointer.as_deref().and_then(|p| lookup(body, p));
+    let repository = lookup(body, &endpoint.repository)
+        .ok_or_else(|| anyhow!("missing field \"{}\"", endpoint.repository))?;
+    let environment = endpoint.environment.as_ref().map(|e| {
+        e.iter()
+            .filter_map(|(name, pointer)| lookup(body, pointer).map(|v| (name.clone(), v)))
+            .collect()
+    });
+    let info = TriggeredInfo {
+        repository,
+        branch: get(&endpoint.branch),
+        tag: get(&endpoint.tag),
+        steps_name: get(&endpoint.steps_name),
+        environment,
+        event: None,
+        changed_files: None,
+        metadata: Metadata::default(),
+    };
+    Ok(info)
+}
+
+/// value of JSON `pointer` as string, `null` is treated as missing
+fn lookup(body: &Value, pointer: &str) -> Option<String> {
+    match body.pointer(pointer)? {
+        Value::Null => None,
+        Value::String(s) => Some(s.clone()),
+        v => Some(v.to_string()),
+    }
+}
@@ -2,9 +2,11 @@ use std::convert::TryInto;
 
 use anyhow::{anyhow, bail, Result};
 use hyper::{header::CONTENT_TYPE, Body, Error, Request, Response};
+use openssl::hash::MessageDigest;
 use serde::Deserialize;
+use serde_json::Value;
 
-use super::TriggeredInfo;
+use super::{changed_files, parse_ref, signature, ChangedFiles, Metadata, TriggeredInfo};
 use crate::constants::CONFIG;
 
 pub async fn trigger(req: Request<Body>) -> Result<Response<Body>, Error> {
@@ -36,30 +38,26 @@ async fn inner_trigger(req: Request<Body>) -> Result<Response<Body>> {
         }
         None => bail!("missing trigger_secret in config"),
     };
-    let payload_signature = signature(&trigger_secret, &body)?;
+    let payload_signature = signature(MessageDigest::sha256(), &trigger_secret, &body)?;
     if header_signature != payload_signature.as_bytes() {
         bail!("signature error");
     }
-    let body: GiteaForm = serde_json::from_slice(&body)?;
-    let info: TriggeredInfo = body.try_into()?;
-    let result = if info.delivery(&CONFIG).await? {
-        "matched"
-    } else {
-        "skipped"
+    let event = match parts.headers.get("X-Gitea-Event") {
+        Some(e) => e.to_str()?,
+        None => "push",
     };
-    Ok(Response::new(Body::from(result)))
-}
-
-fn signature(key: &str, payload: &[u8]) -> Result<String> {
-    use openssl::{hash::MessageDigest, pkey::PKey, sign::Signer};
-
-    let key = PKey::hmac(key.as_bytes())?;
-    let mut signer = Signer::new(MessageDigest::sha256(), &key)?;
-    signer.update(payload)?;
-    let hmac = signer.sign_to_vec()?;
-    Ok(hex::encode(hmac))
+    let info: TriggeredInfo = match event {
+        "push" => serde_json::from_slice::<GiteaForm>(&body)?.try_into()?,
+        "pull_request" => serde_json::from_slice::<PullRequestForm>(&body)?.try_into()?,
+        "release" => serde_json::from_slice::<ReleaseForm>(&body)?.try_into()?,
+        "create" | "delete" => serde_json::from_slice::<RefForm>(&body)?.into_info(event)?,
+        _ => bail!("unsupported event"),
+    };
+    let result = info.delivery(&CONFIG).await?;
+    Ok(result.into())
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, Deserialize)]
 pub struct GiteaForm {
     secret: String,
@@ -69,7 +67,7 @@ pub struct GiteaForm {
     after: String,
     compare_url: String,
     commits: Vec<Commit>,
-    head_commit: Option<String>,
+    head_commit: Option<Commit>,
     repository: Repository,
     pusher: User,
     sender: User,
@@ -79,31 +77,186 @@ impl TryInto<TriggeredInfo> for GiteaForm {
     type Error = anyhow::Error;
 
     fn try_into(self) -> Result<TriggeredInfo> {
-        let section: Vec<&str> = self._ref.split('/').collect();
-        if section.len() != 3 {
-            bail!("invalid field \"ref\"");
-        }
-        if !(section[0] == "refs" && (section[1] == "heads" || section[1] == "tags")) {
-            bail!("invalid field \"ref\"");
-        }
-        let (branch, tag) = if section[1] == "heads" {
-            (Some(section[2]), None)
-        } else if section[1] == "tags" {
-            (None, Some(section[2]))
+        let (branch, tag) = parse_ref(&self._ref)?;
+        let head_commit = self
+            .head_commit
+            .as_ref()
+            .or_else(|| self.commits.iter().find(|c| c.id == self.after));
+        let metadata = Metadata {
+            message: head_commit.map(|c| c.message.clone()),
+            author: head_commit.map(|c| c.author.name.clone()),
+            before: Some(self.before),
+            after: Some(self.after),
+            compare_url: Some(self.compare_url),
+            pusher: Some(self.pusher.login),
+            clone_url: Some(self.repository.clone_url),
+        };
+        let info = TriggeredInfo {
+            repository: self.repository.full_name,
+            branch,
+            tag,
+            steps_name: None,
+            environment: None,
+            event: Some("push".to_string()),
+            changed_files: changed_files(self.commits.iter().map(|c| &c.files)),
+            metadata,
+        };
+        Ok(info)
+    }
+}
+
+/// `pull_request` event, `base` branch of pull request is used as the triggered branch
+#[allow(dead_code)]
+#[derive(Debug, Clone, Deserialize)]
+pub struct PullRequestForm {
+    action: String,
+    number: i64,
+    pull_request: PullRequest,
+    repository: Repository,
+    sender: User,
+}
+
+impl TryInto<TriggeredInfo> for PullRequestForm {
+    type Error = anyhow::Error;
+
+    fn try_into(self) -> Result<TriggeredInfo> {
+        let action = if self.action == "closed" && self.pull_request.merged {
+            "merged"
         } else {
-            bail!("invalid field \"ref\": {}", self._ref);
+            self.action.as_str()
+        };
+        let environment = [
+            (
+                "TRIGGERED_INFO_PULL_REQUEST_NUMBER",
+                self.number.to_string(),
+            ),
+            ("TRIGGERED_INFO_HEAD_BRANCH", self.pull_request.head._ref),
+        ]
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.clone()))
+        .collect();
+        let info = TriggeredInfo {
+            repository: self.repository.full_name,
+            branch: Some(self.pull_request.base._ref),
+            tag: None,
+            steps_name: None,
+            environment: Some(environment),
+            event: Some(format!("pull_request.{}", action)),
+            changed_files: None,
+            metadata: Metadata {
+                after: Some(self.pull_request.head.sha),
+                pusher: Some(self.sender.login),
+                clone_url: Some(self.repository.clone_url),
+                ..Default::default()
+            },
+        };
+        Ok(info)
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Deserialize)]
+pub struct PullRequest {
+    id: i64,
+    number: i64,
+    title: String,
+    merged: bool,
+    head: PullRequestBranch,
+    base: PullRequestBranch,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Deserialize)]
+pub struct PullRequestBranch {
+    label: String,
+    #[serde(rename = "ref")]
+    _ref: String,
+    sha: String,
+}
+
+/// `release` event, tag name of release is used as the triggered tag
+#[allow(dead_code)]
+#[derive(Debug, Clone, Deserialize)]
+pub struct ReleaseForm {
+    action: String,
+    release: Release,
+    repository: Repository,
+    sender: User,
+}
+
+impl TryInto<TriggeredInfo> for ReleaseForm {
+    type Error = anyhow::Error;
+
+    fn try_into(self) -> Result<TriggeredInfo> {
+        let info = TriggeredInfo {
+            repository: self.repository.full_name,
+            branch: None,
+            tag: Some(self.release.tag_name),
+            steps_name: None,
+            environment: None,
+            event: Some(format!("release.{}", self.action)),
+            changed_files: None,
+            metadata: Metadata {
+                pusher: Some(self.sender.login),
+                clone_url: Some(self.repository.clone_url),
+                ..Default::default()
+            },
         };
+        Ok(info)
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Deserialize)]
+pub struct Release {
+    id: i64,
+    tag_name: String,
+    target_commitish: String,
+    name: String,
+    draft: bool,
+    prerelease: bool,
+}
 
+/// `create` and `delete` event, `ref` is a short name of branch or tag
+#[allow(dead_code)]
+#[derive(Debug, Clone, Deserialize)]
+pub struct RefForm {
+    #[serde(rename = "ref")]
+    _ref: String,
+    ref_type: String,
+    /// only present in `create` event
+    sha: Option<String>,
+    repository: Repository,
+    sender: User,
+}
+
+impl RefForm {
+    fn into_info(self, event: &str) -> Result<TriggeredInfo> {
+        let (branch, tag) = match self.ref_type.as_str() {
+            "branch" => (Some(self._ref), None),
+            "tag" => (None, Some(self._ref)),
+            _ => bail!("invalid field \"ref_type\": {}", self.ref_type),
+        };
         let info = TriggeredInfo {
             repository: self.repository.full_name,
-            branch: branch.map(ToString::to_string),
-            tag: tag.map(ToString::to_string),
+            branch,
+            tag,
             steps_name: None,
+            environment: None,
+            event: Some(event.to_string()),
+            changed_files: None,
+            metadata: Metadata {
+                after: self.sha,
+                pusher: Some(self.sender.login),
+                clone_url: Some(self.repository.clone_url),
+                ..Default::default()
+            },
         };
         Ok(info)
     }
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, Deserialize)]
 pub struct Commit {
     id: String,
@@ -111,13 +264,13 @@ pub struct Commit {
     url: String,
     author: Author,
     committer: Author,
-    verification: Option<String>,
+    verification: Option<Value>,
     timestamp: String,
-    added: Option<Vec<String>>,
-    removed: Option<Vec<String>>,
-    modified: Option<Vec<String>>,
+    #[serde(flatten)]
+    files: ChangedFiles,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, Deserialize)]
 pub struct Repository {
     id: i32,
@@ -160,6 +313,7 @@ pub struct Repository {
     avatar_url: String,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, Deserialize)]
 pub struct User {
     id: i32,
@@ -174,6 +328,7 @@ pub struct User {
     username: String,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, Deserialize)]
 pub struct Author {
     name: String,
@@ -181,6 +336,7 @@ pub struct Author {
     username: String,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, Deserialize)]
 pub struct Permissions {
     admin: bool,
@@ -188,6 +344,7 @@ pub struct Permissions {
     pull: bool,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, Deserialize)]
 pub struct InternalTracker {
     enable_time_tracker: bool,
@@ -0,0 +1,129 @@
+use std::convert::TryInto;
+
+use anyhow::{anyhow, bail, Result};
+use hyper::{header::CONTENT_TYPE, Body, Error, Request, Response};
+use openssl::hash::MessageDigest;
+use serde::Deserialize;
+
+use super::{
+    changed_files, parse_ref, secure_eq, signature, ChangedFiles, Metadata, TriggeredInfo,
+};
+use crate::constants::CONFIG;
+
+/// github webhook, handle `ping` and `push` event
+pub async fn trigger(req: Request<Body>) -> Result<Response<Body>, Error> {
+    match inner_trigger(req).await {
+        Ok(r) => Ok(r),
+        Err(e) => Ok(Response::new(Body::from(e.to_string()))),
+    }
+}
+
+async fn inner_trigger(req: Request<Body>) -> Result<Response<Body>> {
+    let (parts, body) = req.into_parts();
+    match parts.headers.get(CONTENT_TYPE) {
+        Some(c) => {
+            if c != "application/json" {
+                bail!("invalid content-type");
+            }
+        }
+        None => bail!("missing content-type"),
+    }
+    let header_signature = parts
+        .headers
+        .get("X-Hub-Signature-256")
+        .ok_or_else(|| anyhow!("missing signature"))?;
+    let body = hyper::body::to_bytes(body).await?;
+    let trigger_secret = match CONFIG.trigger.as_ref().and_then(|t| t.github.as_ref()) {
+        Some(github) => &github.secret,
+        None => bail!("missing trigger.github.secret in config"),
+    };
+    let payload_signature = format!(
+        "sha256={}",
+        signature(MessageDigest::sha256(), trigger_secret, &body)?
+    );
+    if !secure_eq(header_signature.as_bytes(), payload_signature.as_bytes()) {
+        bail!("signature error");
+    }
+    let event = parts
+        .headers
+        .get("X-GitHub-Event")
+        .ok_or_else(|| anyhow!("missing event"))?;
+    if event == "ping" {
+        return Ok(Response::new(Body::from("pong")));
+    }
+    if event != "push" {
+        bail!("unsupported event");
+    }
+    let body: GithubForm = serde_json::from_slice(&body)?;
+    if body.deleted {
+        return Ok(Response::new(Body::from("skipped")));
+    }
+    let info: TriggeredInfo = body.try_into()?;
+    let result = info.delivery(&CONFIG).await?;
+    Ok(result.into())
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GithubForm {
+    #[serde(rename = "ref")]
+    _ref: String,
+    before: Option<String>,
+    after: Option<String>,
+    compare: Option<String>,
+    #[serde(default)]
+    deleted: bool,
+    head_commit: Option<Commit>,
+    repository: Repository,
+    sender: Option<User>,
+    #[serde(default)]
+    commits: Vec<ChangedFiles>,
+}
+
+impl TryInto<TriggeredInfo> for GithubForm {
+    type Error = anyhow::Error;
+
+    fn try_into(self) -> Result<TriggeredInfo> {
+        let (branch, tag) = parse_ref(&self._ref)?;
+        let info = TriggeredInfo {
+            repository: self.repository.full_name,
+            branch,
+            tag,
+            steps_name: None,
+            environment: None,
+            event: Some("push".to_string()),
+            changed_files: changed_files(&self.commits),
+            metadata: Metadata {
+                message: self.head_commit.as_ref().map(|c| c.message.clone()),
+                author: self.head_commit.map(|c| c.author.name),
+                before: self.before,
+                after: self.after,
+                compare_url: self.compare,
+                pusher: self.sender.map(|s| s.login),
+                clone_url: self.repository.clone_url,
+            },
+        };
+        Ok(info)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Repository {
+    pub full_name: String,
+    pub clone_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Commit {
+    message: String,
+    author: Author,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Author {
+    name: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct User {
+    login: String,
+}
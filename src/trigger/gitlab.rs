@@ -0,0 +1,134 @@
+use std::convert::TryInto;
+
+use anyhow::{anyhow, bail, Result};
+use hyper::{header::CONTENT_TYPE, Body, Error, Request, Response};
+use serde::Deserialize;
+
+use super::{changed_files, parse_ref, secure_eq, ChangedFiles, Metadata, TriggeredInfo};
+use crate::constants::CONFIG;
+
+/// gitlab webhook, handle `Push Hook` and `Tag Push Hook` event
+pub async fn trigger(req: Request<Body>) -> Result<Response<Body>, Error> {
+    match inner_trigger(req).await {
+        Ok(r) => Ok(r),
+        Err(e) => Ok(Response::new(Body::from(e.to_string()))),
+    }
+}
+
+async fn inner_trigger(req: Request<Body>) -> Result<Response<Body>> {
+    let (parts, body) = req.into_parts();
+    match parts.headers.get(CONTENT_TYPE) {
+        Some(c) => {
+            if c != "application/json" {
+                bail!("invalid content-type");
+            }
+        }
+        None => bail!("missing content-type"),
+    }
+    let header_token = parts
+        .headers
+        .get("X-Gitlab-Token")
+        .ok_or_else(|| anyhow!("missing token"))?;
+    let trigger_token = match CONFIG.trigger.as_ref().and_then(|t| t.gitlab.as_ref()) {
+        Some(gitlab) => &gitlab.secret,
+        None => bail!("missing trigger.gitlab.secret in config"),
+    };
+    if !secure_eq(header_token.as_bytes(), trigger_token.as_bytes()) {
+        bail!("token error");
+    }
+    let event = parts
+        .headers
+        .get("X-Gitlab-Event")
+        .ok_or_else(|| anyhow!("missing event"))?;
+    let is_tag = if event == "Push Hook" {
+        false
+    } else if event == "Tag Push Hook" {
+        true
+    } else {
+        bail!("unsupported event");
+    };
+    let body = hyper::body::to_bytes(body).await?;
+    let body: GitlabForm = serde_json::from_slice(&body)?;
+    // `checkout_sha` is null when a branch or tag is deleted
+    if body.checkout_sha.is_none() {
+        return Ok(Response::new(Body::from("skipped")));
+    }
+    let info: TriggeredInfo = body.try_into()?;
+    if info.tag.is_some() != is_tag {
+        bail!("invalid field \"ref\"");
+    }
+    let result = info.delivery(&CONFIG).await?;
+    Ok(result.into())
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct GitlabForm {
+    #[serde(rename = "ref")]
+    _ref: String,
+    before: Option<String>,
+    after: Option<String>,
+    checkout_sha: Option<String>,
+    user_username: Option<String>,
+    project: Project,
+    #[serde(default)]
+    commits: Vec<Commit>,
+}
+
+impl TryInto<TriggeredInfo> for GitlabForm {
+    type Error = anyhow::Error;
+
+    fn try_into(self) -> Result<TriggeredInfo> {
+        let (branch, tag) = parse_ref(&self._ref)?;
+        let head_commit = self
+            .commits
+            .iter()
+            .find(|c| Some(&c.id) == self.checkout_sha.as_ref());
+        let compare_url = match (&self.project.web_url, &self.before, &self.after) {
+            (Some(web_url), Some(before), Some(after)) => {
+                Some(format!("{}/-/compare/{}...{}", web_url, before, after))
+            }
+            _ => None,
+        };
+        let metadata = Metadata {
+            message: head_commit.map(|c| c.message.clone()),
+            author: head_commit.map(|c| c.author.name.clone()),
+            before: self.before,
+            after: self.after,
+            compare_url,
+            pusher: self.user_username,
+            clone_url: self.project.git_http_url,
+        };
+        let info = TriggeredInfo {
+            repository: self.project.path_with_namespace,
+            branch,
+            tag,
+            steps_name: None,
+            environment: None,
+            event: Some("push".to_string()),
+            changed_files: changed_files(self.commits.iter().map(|c| &c.files)),
+            metadata,
+        };
+        Ok(info)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Project {
+    pub path_with_namespace: String,
+    pub web_url: Option<String>,
+    pub git_http_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Commit {
+    id: String,
+    message: String,
+    author: Author,
+    #[serde(flatten)]
+    files: ChangedFiles,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Author {
+    name: String,
+}
@@ -22,10 +22,6 @@ async fn inner_trigger(req: Request<Body>) -> Result<Response<Body>> {
     let body = req.into_body();
     let body = hyper::body::to_bytes(body).await?;
     let body: TriggeredInfo = serde_json::from_slice(&body)?;
-    let result = if body.delivery(&CONFIG).await? {
-        "matched"
-    } else {
-        "skipped"
-    };
-    Ok(Response::new(Body::from(result)))
+    let result = body.delivery(&CONFIG).await?;
+    Ok(result.into())
 }
@@ -1,15 +1,36 @@
-use anyhow::Result;
+use std::{
+    collections::HashMap,
+    fmt::{self, Display, Formatter},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Result};
+use hyper::{Body, Response, StatusCode};
 use log::error;
+use openssl::hash::MessageDigest;
 use serde::Deserialize;
+use tokio::fs::{create_dir_all, write};
 
-use crate::config::Config;
+use self::directive::Directive;
+use crate::{
+    config::{Config, Repository},
+    constants::CONFIG,
+    executor::{queue, worker},
+};
 
-mod gitea;
 mod coding;
+mod directive;
+mod generic;
+mod gitea;
+mod github;
+mod gitlab;
 mod manual;
 
-pub use gitea::trigger as gitea_trigger;
 pub use coding::trigger as coding_trigger;
+pub use generic::trigger as generic_trigger;
+pub use gitea::trigger as gitea_trigger;
+pub use github::trigger as github_trigger;
+pub use gitlab::trigger as gitlab_trigger;
 pub use manual::trigger as manual_trigger;
 
 #[derive(Debug, Clone, Deserialize)]
@@ -18,33 +39,276 @@ pub struct TriggeredInfo {
     pub branch: Option<String>,
     pub tag: Option<String>,
     pub steps_name: Option<String>,
+    /// extra environment variables exported to steps, only set by triggers in code,
+    /// a request body of the manual trigger must not override `PATH`, `LD_PRELOAD`, etc.
+    #[serde(skip_deserializing)]
+    pub environment: Option<HashMap<String, String>>,
+    /// webhook event, e.g. `push`, `pull_request.opened`, `release.published`,
+    /// events are not filtered when missing
+    pub event: Option<String>,
+    /// files changed by the push, path filters are not applied when missing,
+    /// they are written to a file exported as `TRIGGERED_INFO_CHANGED_FILES_PATH`
+    pub changed_files: Option<Vec<String>>,
+    #[serde(flatten)]
+    pub metadata: Metadata,
+}
+
+/// commit metadata, exported to steps as `TRIGGERED_INFO_*` environment variables
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Metadata {
+    /// commit SHA before the push
+    pub before: Option<String>,
+    /// commit SHA after the push
+    pub after: Option<String>,
+    pub compare_url: Option<String>,
+    /// login of the user who pushed
+    pub pusher: Option<String>,
+    /// head commit message, may contain directives such as `[skip deploy]`, `[deploy:prod]`
+    pub message: Option<String>,
+    /// head commit author
+    pub author: Option<String>,
+    pub clone_url: Option<String>,
+}
+
+/// files changed by a commit
+#[derive(Debug, Clone, Deserialize)]
+pub struct ChangedFiles {
+    added: Option<Vec<String>>,
+    removed: Option<Vec<String>>,
+    modified: Option<Vec<String>>,
+}
+
+/// files changed by `commits`, sorted and deduplicated, `None` if there is no file
+fn changed_files<'a>(commits: impl IntoIterator<Item = &'a ChangedFiles>) -> Option<Vec<String>> {
+    let mut files: Vec<String> = commits
+        .into_iter()
+        .flat_map(|c| c.added.iter().chain(&c.removed).chain(&c.modified))
+        .flatten()
+        .cloned()
+        .collect();
+    files.sort();
+    files.dedup();
+    if files.is_empty() {
+        None
+    } else {
+        Some(files)
+    }
 }
 
 impl TriggeredInfo {
-    pub async fn delivery(self, config: &'static Config) -> Result<bool> {
-        if let Some(repo) = config.repository.iter().find(|i| i.name == self.repository) {
-            if let Some(branch) = &repo.branch {
-                if !match &self.branch {
-                    Some(b) => branch == "@any" || b == branch,
-                    None => false,
-                } {
-                    return Ok(false);
-                }
+    /// run every matched repository entry, return labels of them
+    pub async fn delivery(mut self, config: &'static Config) -> Result<Delivery> {
+        match self.metadata.message.as_deref().and_then(Directive::parse) {
+            Some(Directive::Skip) => return Ok(Delivery::Directive),
+            Some(Directive::Steps(steps_name)) if self.steps_name.is_none() => {
+                self.steps_name = Some(steps_name.to_string());
             }
-            if let Some(tag) = &repo.tag {
-                if !match &self.tag {
-                    Some(t) => tag == "@any" || t == tag,
-                    None => false,
-                } {
-                    return Ok(false);
-                }
+            _ => {}
+        }
+        if worker::is_busy() {
+            return Ok(Delivery::Busy);
+        }
+        let mut matched = Vec::new();
+        for (index, repo) in config.repository.iter().enumerate() {
+            if repo.name != self.repository {
+                continue;
             }
-            tokio::spawn(async move {
-                if let Err(e) = repo.execute(&self).await {
-                    error!("delivery execute error: {}", e);
+            let info = match self.match_repository(repo) {
+                Some(info) => info,
+                None => continue,
+            };
+            matched.push(repo.label(index));
+            info.spawn(index, repo);
+        }
+        Ok(if matched.is_empty() {
+            Delivery::Skipped
+        } else {
+            Delivery::Matched(matched)
+        })
+    }
+
+    /// execute steps of `repo` in background, runs of the same repository entry are queued,
+    /// `index` is the position of `repo` in the config, entries of the same name are independent
+    pub fn spawn(mut self, index: usize, repo: &'static Repository) {
+        let concurrency = repo.concurrency.clone().unwrap_or_default();
+        let mut key = format!("{}\n{}", index, repo.name);
+        if concurrency.per_steps {
+            key = format!("{}\n{}", key, self.steps_name.as_deref().unwrap_or(""));
+        }
+        queue::submit(key, concurrency.policy, async move {
+            let _worker = worker::acquire().await;
+            // the list may exceed the limit of an environment variable, so it is written to
+            // a file, which is removed when the run is finished
+            let _changed_files = match &self.changed_files {
+                Some(files) => match ChangedFilesFile::write(files).await {
+                    Ok(file) => {
+                        self.environment.get_or_insert_with(HashMap::new).insert(
+                            "TRIGGERED_INFO_CHANGED_FILES_PATH".to_string(),
+                            file.0.to_string_lossy().to_string(),
+                        );
+                        Some(file)
+                    }
+                    Err(e) => {
+                        error!("write changed files error: {}", e);
+                        return;
+                    }
+                },
+                None => None,
+            };
+            if let Err(e) = repo.execute(&self).await {
+                error!("delivery execute error: {}", e);
+            }
+        });
+    }
+
+    /// check event, branch and tag against `repo`, steps name is resolved if matched
+    fn match_repository(&self, repo: &Repository) -> Option<TriggeredInfo> {
+        let mut info = self.clone();
+        if let Some(event) = &self.event {
+            match &repo.events {
+                Some(events) => {
+                    let steps_name = events.get(event)?;
+                    if info.steps_name.is_none() {
+                        info.steps_name = steps_name.clone();
+                    }
+                }
+                None => {
+                    if event != "push" {
+                        return None;
+                    }
                 }
-            });
+            }
+        }
+        if let Some(branch) = &repo.branch {
+            if !branch.is_match(self.branch.as_deref()) {
+                return None;
+            }
+        }
+        if let Some(tag) = &repo.tag {
+            if !tag.is_match(self.tag.as_deref()) {
+                return None;
+            }
+        }
+        if info.steps_name.is_none() {
+            info.steps_name = repo
+                .match_steps_name(self.branch.as_deref(), self.tag.as_deref())
+                .map(ToString::to_string);
+        }
+        if let Some(files) = &self.changed_files {
+            if !repo.match_paths(info.steps_name.as_deref(), files) {
+                return None;
+            }
         }
-        Ok(true)
+        Some(info)
+    }
+}
+
+/// changed files of a run, one per line, the file is removed on drop
+struct ChangedFilesFile(PathBuf);
+
+impl ChangedFilesFile {
+    async fn write(files: &[String]) -> Result<ChangedFilesFile> {
+        let dir = CONFIG.work_dir.as_deref().unwrap_or("/tmp");
+        let dir = Path::new(dir).join("cache").join("changed_files");
+        create_dir_all(&dir).await?;
+        let file = ChangedFilesFile(dir.join(crate::tmp_filename(16)));
+        let mut content = files.join("\n");
+        content.push('\n');
+        write(&file.0, content).await?;
+        Ok(file)
+    }
+}
+
+impl Drop for ChangedFilesFile {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                error!("remove {} error: {}", self.0.to_string_lossy(), e);
+            }
+        }
+    }
+}
+
+pub enum Delivery {
+    /// labels of triggered repository entries
+    Matched(Vec<String>),
+    Skipped,
+    /// skipped by directive in commit message
+    Directive,
+    /// rejected since too many runs are not started
+    Busy,
+}
+
+impl From<Delivery> for Response<Body> {
+    fn from(delivery: Delivery) -> Response<Body> {
+        let mut resp = Response::new(Body::from(delivery.to_string()));
+        if let Delivery::Busy = delivery {
+            *resp.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+        }
+        resp
+    }
+}
+
+impl Display for Delivery {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Delivery::Matched(m) => write!(f, "matched: {}", m.join(", ")),
+            Delivery::Skipped => write!(f, "skipped"),
+            Delivery::Directive => write!(f, "skipped: directive"),
+            Delivery::Busy => write!(f, "busy"),
+        }
+    }
+}
+
+/// hex encoded HMAC of `payload`, used to verify webhook signature
+fn signature(digest: MessageDigest, key: &str, payload: &[u8]) -> Result<String> {
+    use openssl::{pkey::PKey, sign::Signer};
+
+    let key = PKey::hmac(key.as_bytes())?;
+    let mut signer = Signer::new(digest, &key)?;
+    signer.update(payload)?;
+    let hmac = signer.sign_to_vec()?;
+    Ok(hex::encode(hmac))
+}
+
+/// constant time comparison of tokens or signatures, `memcmp::eq` panics on different lengths
+fn secure_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && openssl::memcmp::eq(a, b)
+}
+
+/// split a git ref into branch and tag,
+/// e.g. `refs/heads/release/2.1` is branch `release/2.1`, `refs/tags/v1.0` is tag `v1.0`
+fn parse_ref(git_ref: &str) -> Result<(Option<String>, Option<String>)> {
+    match git_ref
+        .strip_prefix("refs/heads/")
+        .map(|b| (b, false))
+        .or_else(|| git_ref.strip_prefix("refs/tags/").map(|t| (t, true)))
+    {
+        Some(("", _)) => bail!("invalid field \"ref\": {}", git_ref),
+        Some((name, false)) => Ok((Some(name.to_string()), None)),
+        Some((name, true)) => Ok((None, Some(name.to_string()))),
+        None => bail!("invalid field \"ref\": {}", git_ref),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ref_with_slash() {
+        let (branch, tag) = parse_ref("refs/heads/feature/login").unwrap();
+        assert_eq!(branch.as_deref(), Some("feature/login"));
+        assert_eq!(tag, None);
+        let (branch, tag) = parse_ref("refs/tags/release/2.1").unwrap();
+        assert_eq!(branch, None);
+        assert_eq!(tag.as_deref(), Some("release/2.1"));
+    }
+
+    #[test]
+    fn parse_invalid_ref() {
+        assert!(parse_ref("refs/heads/").is_err());
+        assert!(parse_ref("refs/tags/").is_err());
+        assert!(parse_ref("refs/pull/1/head").is_err());
     }
 }